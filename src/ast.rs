@@ -0,0 +1,137 @@
+use serde::{Serialize, Deserialize};
+use crate::lex::Span;
+
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Comment {
+    pub text: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Dependency {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Header {
+    pub label: String,
+    pub dependencies: Vec<Dependency>,
+    pub span: Span,
+}
+
+/// A step line, its `@` (silent) and `-` (ignore errors) prefixes taken
+/// off the command. Lines of a `#!` script keep them as they are.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StepNode {
+    pub command: String,
+    pub silent: bool,
+    pub ignore_errors: bool,
+    pub span: Span,
+}
+
+/// `[NAME value]` on the line above an instruction header, a setting that
+/// only applies to that instruction.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attribute {
+    pub name: String,
+    pub value: String,
+    pub span: Span,
+}
+
+/// An instruction as written in the Bakefile: its attributes and header
+/// line followed by the indented steps and comments of its body.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstructionNode {
+    pub attributes: Vec<Attribute>,
+    pub header: Header,
+    pub steps: Vec<StepNode>,
+    pub comments: Vec<Comment>,
+    pub span: Span,
+}
+
+impl InstructionNode {
+    pub fn new(header: Header) -> InstructionNode {
+        InstructionNode {
+            span: header.span,
+            attributes: Vec::new(),
+            header,
+            steps: Vec::new(),
+            comments: Vec::new(),
+        }
+    }
+    pub fn label(&self) -> &str {
+        &self.header.label
+    }
+    pub fn extend_span(&mut self, span: &Span) {
+        self.span = self.span.to(span);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AssignmentKind {
+    Set,
+    Append,
+    Command,
+}
+
+/// An ingredient declaration such as `NAME = value`, `NAME += value` or
+/// `NAME != command`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IngredientNode {
+    pub name: String,
+    pub kind: AssignmentKind,
+    pub value: String,
+    pub span: Span,
+}
+
+/// `set NAME value`, a setting of the whole recipe.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Setting {
+    pub name: String,
+    pub value: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Item {
+    Instruction(InstructionNode),
+    Ingredient(IngredientNode),
+    Setting(Setting),
+    Comment(Comment),
+}
+
+/// Syntax tree of a whole Bakefile, items kept in source order.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Document {
+    pub items: Vec<Item>,
+}
+
+impl Document {
+    pub fn instructions(&self) -> impl Iterator<Item = &InstructionNode> {
+        self.items.iter().filter_map(|item| match item {
+            Item::Instruction(instruction) => Some(instruction),
+            _ => None,
+        })
+    }
+    pub fn ingredients(&self) -> impl Iterator<Item = &IngredientNode> {
+        self.items.iter().filter_map(|item| match item {
+            Item::Ingredient(ingredient) => Some(ingredient),
+            _ => None,
+        })
+    }
+    pub fn settings(&self) -> impl Iterator<Item = &Setting> {
+        self.items.iter().filter_map(|item| match item {
+            Item::Setting(setting) => Some(setting),
+            _ => None,
+        })
+    }
+    pub fn comments(&self) -> impl Iterator<Item = &Comment> {
+        self.items.iter().flat_map(|item| match item {
+            Item::Instruction(instruction) => instruction.comments.iter().collect::<Vec<&Comment>>(),
+            Item::Ingredient(_) | Item::Setting(_) => Vec::new(),
+            Item::Comment(comment) => vec![comment],
+        })
+    }
+}
@@ -1,58 +1,766 @@
-pub use crate::ing::{Instruction, Recipe};
-pub use crate::execute::{Bash, Sh};
+pub use crate::ing::{Instruction, Recipe, Step};
+pub use crate::execute::{Bash, Interpreter, Sh, TempFile};
 pub use crate::execute::Shell;
 pub use std::process::{Command, Output};
+use crate::errors::Error;
+use crate::report::{InstructionPlan, InstructionReport, Plan, Report, StepReport};
 use sanitation::SString;
+use std::collections::BTreeSet;
 use std::io::{self, Write};
+use std::sync::{mpsc, Mutex, PoisonError};
+use std::thread;
+use std::time::Instant;
+
+/// How the steps of an instruction are run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// each step by a shell of its own
+    Steps,
+    /// all steps as the `#!` script they make up
+    Script,
+    /// all steps by a single shell, stopping at the first that fails
+    Session,
+}
+
+/// The steps of a oneshell instruction as one POSIX shell script. Before each
+/// step its index is written to `status` so a failure can be blamed on it,
+/// the failure of a `-` step is ignored.
+fn session(steps: &[Step], status: &str) -> String {
+    let status = format!("'{}'", status.replace('\'', "'\\''"));
+    let mut script = String::from("set -e\n");
+    for (index, step) in steps.iter().enumerate() {
+        script.push_str(&format!("echo {} > {}\n", index, status));
+        if step.ignore_errors {
+            script.push_str(&format!("{{ {}\n}} || true\n", step.command));
+        } else {
+            script.push_str(&format!("{}\n", step.command));
+        }
+    }
+    script
+}
+
+/// How much the baker tells about the steps it runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Verbosity {
+    /// only the output of steps that fail
+    Quiet,
+    /// the output of every step
+    #[default]
+    Normal,
+    /// the output of every step, each step preceded by its command
+    Verbose,
+}
+
+/// The commands of `steps` as the trace mode shows them, each line prefixed
+/// with the instruction label. Silent steps are left out.
+fn trace(label: &str, steps: &[Step]) -> String {
+    steps
+        .iter()
+        .filter(|step| !step.silent)
+        .flat_map(|step| step.command.lines())
+        .map(|line| format!("[{}] {}\n", label, line))
+        .collect()
+}
 
 pub struct Baker {
     cwd: Option<String>,
     safe: bool,
     instructions: Vec<String>,
+    jobs: usize,
+    keep_going: bool,
+    shell: Option<String>,
+    verbosity: Verbosity,
 }
 
 impl Baker {
     pub fn new(cwd: Option<String>, safe: bool, instructions: Vec<String>) -> Baker {
         Baker{
-            cwd: cwd,
-            safe: safe,
-            instructions: instructions,
+            cwd,
+            safe,
+            instructions,
+            jobs: 1,
+            keep_going: false,
+            shell: None,
+            verbosity: Verbosity::Normal,
         }
     }
-    pub fn perform(&self, recipe: Recipe) {
-        // let mut performed = Vec::<String>::new();
-        if self.instructions.len() == 0 {
-            self.execute_instruction(&recipe.main_instruction().unwrap())
-        } else {
-            for label in &self.instructions {
-                for instruction in recipe.get_instructions(label) {
-                    self.execute_instruction(&instruction)
+    /// Allows up to `jobs` independent instructions to run at the same time.
+    pub fn with_jobs(mut self, jobs: usize) -> Baker {
+        self.jobs = jobs.max(1);
+        self
+    }
+    /// Keeps performing the instructions that don't depend on a failed one
+    /// instead of stopping at the first failure.
+    pub fn with_keep_going(mut self, keep_going: bool) -> Baker {
+        self.keep_going = keep_going;
+        self
+    }
+    /// Runs steps with `shell`, such as `bash -euo pipefail -c`, instead of
+    /// the one set by the recipe. Instructions that name their own shell
+    /// still use it.
+    pub fn with_shell(mut self, shell: Option<String>) -> Baker {
+        self.shell = shell;
+        self
+    }
+    pub fn with_verbosity(mut self, verbosity: Verbosity) -> Baker {
+        self.verbosity = verbosity;
+        self
+    }
+    /// Follows the requested instructions, each preceded by everything it
+    /// depends on. An instruction runs at most once per call, no matter how
+    /// many others depend on it. Failed steps don't make this an error, they
+    /// are part of the returned report.
+    pub fn perform(&self, recipe: Recipe) -> Result<Report, Error> {
+        let order = recipe.graph().order(&self.targets(&recipe)?)?;
+        self.bake(recipe, order)
+    }
+    /// What `perform` would do, without running anything. Command
+    /// ingredients aren't evaluated either, they show up as `$(command)`.
+    pub fn plan(&self, mut recipe: Recipe) -> Result<Plan, Error> {
+        let mut order = Vec::<String>::new();
+        for target in self.targets(&recipe)? {
+            let labels = recipe.resolve_dependencies(&target)?.ok_or(Error::UnknownInstruction(target))?;
+            for label in labels {
+                if !order.contains(&label) {
+                    order.push(label);
                 }
             }
         }
+        let mut instructions = Vec::<InstructionPlan>::new();
+        for label in &order {
+            for instruction in recipe.get_instructions(label) {
+                let shell = self.shell(&recipe, &instruction);
+                let commands = recipe.preview_instruction(&instruction)?;
+                let steps = instruction.actions().into_iter().zip(commands).map(|(step, command)| Step { command, ..step }).collect();
+                let shell = if instruction.is_script() {
+                    let shebang = instruction.steps()[0].clone();
+                    format!("{} (script)", Interpreter::shebang(&shebang, None).map(|i| i.to_string()).unwrap_or(shebang))
+                } else if instruction.oneshell() || recipe.oneshell() {
+                    if !shell.is_posix() {
+                        return Err(Error::UnsupportedOneshell { instruction: instruction.name(), shell: shell.to_string() });
+                    }
+                    format!("{} (oneshell)", shell)
+                } else {
+                    shell.to_string()
+                };
+                instructions.push(InstructionPlan { label: label.clone(), shell, steps });
+            }
+        }
+        let cwd = self.cwd.clone().unwrap_or(".".to_string());
+        let cwd = std::fs::canonicalize(&cwd).map(|path| path.display().to_string()).unwrap_or(cwd);
+        Ok(Plan { cwd, order, instructions })
     }
-    pub fn execute_instruction(&self, instruction: &Instruction) {
-        for step in instruction.steps() {
-            let output = Sh::new(self.cwd.clone()).execute(&step).expect(&format!("failed execute step: {:?}", step));
-            if self.safe {
-                let stdout = SString::new(&output.stdout);
-                let stderr = SString::new(&output.stderr);
-                println!("{}", stdout.soft_word());
-                eprintln!("{}", stderr.soft_word());
-            } else {
-                io::stdout().write_all(&output.stdout).expect(&format!("failed to write the output of {:?} to stdout", &step));
-                io::stderr().write_all(&output.stderr).expect(&format!("failed to write the output of {:?} to stderr", &step));
+    /// The labels to follow: the requested ones or else the main instruction.
+    /// A label that isn't in the recipe is an error naming the closest ones.
+    fn targets(&self, recipe: &Recipe) -> Result<Vec<String>, Error> {
+        if self.instructions.is_empty() {
+            return Ok(vec![recipe.main_instruction()?.name()]);
+        }
+        let graph = recipe.graph();
+        for label in &self.instructions {
+            if !graph.contains(label) {
+                return Err(Error::UnknownTarget {
+                    label: label.clone(),
+                    targets: graph.labels(),
+                    suggestions: graph.suggestions(label),
+                });
             }
-            match output.status.code() {
-                Some(code) => {
-                    if code != 0 {
-                        std::process::exit(code);
-                    }
-                },
-                None => {
-                    std::process::exit(3);
+        }
+        Ok(self.instructions.clone())
+    }
+    /// The shell that runs the steps of `instruction`. Its own wins over
+    /// --shell, which wins over `set shell`.
+    fn shell(&self, recipe: &Recipe, instruction: &Instruction) -> Interpreter {
+        let spec = instruction.shell().or(self.shell.clone()).or(recipe.shell()).unwrap_or_default();
+        Interpreter::parse(&spec, self.cwd.clone())
+    }
+    /// Performs the instructions of `order`, starting each one as soon as
+    /// its dependencies are done and no more than `jobs` at a time. After a
+    /// failure nothing new is started and the running instructions are
+    /// waited for, unless keeping going, in which case only the instructions
+    /// that depend on the failed one are skipped.
+    fn bake(&self, recipe: Recipe, order: Vec<String>) -> Result<Report, Error> {
+        let graph = recipe.graph();
+        let recipe = Mutex::new(recipe);
+        let mut waiting = order;
+        let mut done = BTreeSet::<String>::new();
+        let mut running = 0;
+        let mut report = Report::default();
+        let mut error: Option<Error> = None;
+        let (sender, receiver) = mpsc::channel::<Result<InstructionReport, Error>>();
+        thread::scope(|scope| {
+            loop {
+                if self.keep_going {
+                    // `waiting` is in dependency order, so a single pass also
+                    // skips whatever depends on a skipped instruction
+                    waiting.retain(|label| {
+                        let blocked = graph.dependencies(label).iter().any(|dependency| {
+                            report.skipped.contains(dependency) || report.failed().iter().any(|failed| &failed.label == dependency)
+                        });
+                        if blocked {
+                            report.skipped.push(label.clone());
+                        }
+                        !blocked
+                    });
+                }
+                while error.is_none() && (report.success() || self.keep_going) && running < self.jobs {
+                    let Some(position) = waiting.iter().position(|label| {
+                        graph.dependencies(label).iter().all(|dependency| done.contains(dependency))
+                    }) else {
+                        break;
+                    };
+                    let label = waiting.remove(position);
+                    let sender = sender.clone();
+                    let recipe = &recipe;
+                    running += 1;
+                    scope.spawn(move || {
+                        let _ = sender.send(self.execute_label(recipe, &label));
+                    });
+                }
+                if running == 0 {
+                    break;
+                }
+                let Ok(result) = receiver.recv() else {
+                    break;
+                };
+                running -= 1;
+                match result {
+                    Ok(instruction) => {
+                        if instruction.success() {
+                            done.insert(instruction.label.clone());
+                        }
+                        report.instructions.push(instruction);
+                    },
+                    Err(e) => {
+                        error.get_or_insert(e);
+                    },
                 }
             }
+        });
+        if let Some(e) = error {
+            return Err(e);
         }
+        report.skipped.extend(waiting);
+        Ok(report)
+    }
+    /// Performs every instruction labeled `label`, reported as one.
+    fn execute_label(&self, recipe: &Mutex<Recipe>, label: &str) -> Result<InstructionReport, Error> {
+        let instructions = recipe.lock().unwrap_or_else(PoisonError::into_inner).get_instructions(label);
+        let mut report = InstructionReport::new(label);
+        for instruction in instructions {
+            let done = self.execute_instruction(recipe, &instruction)?;
+            report.steps.extend(done.steps);
+            report.duration += done.duration;
+            if done.error.is_some() {
+                report.error = done.error;
+                break;
+            }
+        }
+        Ok(report)
+    }
+    /// Runs the steps of `instruction` until one of them fails. A `#!`
+    /// instruction runs as a whole script, a oneshell one by a single shell.
+    /// A failing step or ingredient ends up in the report, an error is only
+    /// returned when the output of a step could not be written.
+    fn execute_instruction(&self, recipe: &Mutex<Recipe>, instruction: &Instruction) -> Result<InstructionReport, Error> {
+        let started = Instant::now();
+        let mut report = InstructionReport::new(&instruction.name());
+        let mode = if instruction.is_script() {
+            Mode::Script
+        } else if instruction.oneshell() || recipe.lock().unwrap_or_else(PoisonError::into_inner).oneshell() {
+            Mode::Session
+        } else {
+            Mode::Steps
+        };
+        let actions = instruction.actions();
+        let groups = match mode {
+            Mode::Steps => actions.into_iter().map(|step| vec![step]).collect(),
+            _ if actions.is_empty() => Vec::new(),
+            _ => vec![actions],
+        };
+        for steps in groups {
+            let started = Instant::now();
+            let (step, culprit, output) = match self.execute_step(recipe, instruction, &steps, mode) {
+                Ok(executed) => executed,
+                Err(e) => {
+                    report.error = Some(e);
+                    break;
+                },
+            };
+            let step = StepReport::new(&step, &output.status, started.elapsed());
+            let failed = step.failed();
+            if failed || self.verbosity != Verbosity::Quiet {
+                self.write_output(&output)?;
+            }
+            report.steps.push(step);
+            if failed {
+                report.error = Some(Error::StepFailed {
+                    instruction: instruction.name(),
+                    step: culprit,
+                    code: output.status.code(),
+                });
+                break;
+            }
+        }
+        report.duration = started.elapsed();
+        Ok(report)
+    }
+    /// Substitutes the ingredients of `steps` and runs them, returning the
+    /// step that actually ran, the command to blame should it fail and its
+    /// output. A script is blamed on its `#!` line and a session on the last
+    /// step that started.
+    fn execute_step(&self, recipe: &Mutex<Recipe>, instruction: &Instruction, steps: &[Step], mode: Mode) -> Result<(Step, String, Output), Error> {
+        // Command ingredients are evaluated on a copy, so that a slow one
+        // doesn't keep the other jobs from starting their steps.
+        let mut local = recipe.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        let shell = self.shell(&local, instruction);
+        let steps = steps
+            .iter()
+            .map(|step| Ok(Step {
+                command: local.translate_step_with(instruction, &step.command, &shell)?,
+                ..step.clone()
+            }))
+            .collect::<Result<Vec<Step>, Error>>();
+        recipe.lock().unwrap_or_else(PoisonError::into_inner).keep_baked(&local);
+        let steps = steps?;
+        if self.verbosity == Verbosity::Verbose {
+            io::stderr().lock().write_all(trace(&instruction.name(), &steps).as_bytes())?;
+        }
+        let lines = steps.iter().map(|step| step.command.clone()).collect::<Vec<String>>();
+        let step = Step {
+            command: lines.join("\n"),
+            silent: steps.iter().all(|step| step.silent),
+            ignore_errors: mode == Mode::Steps && steps[0].ignore_errors,
+        };
+        match mode {
+            Mode::Steps => {
+                let output = self.run(&shell, &step.command)?;
+                let culprit = step.command.clone();
+                Ok((step, culprit, output))
+            },
+            Mode::Script => {
+                let script = TempFile::create(&format!("{}\n", step.command))?;
+                let shebang = lines[0].clone();
+                let interpreter = Interpreter::shebang(&shebang, self.cwd.clone()).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} names no interpreter", shebang))
+                })?;
+                let output = self.run(&interpreter, &script.path())?;
+                Ok((step, shebang, output))
+            },
+            Mode::Session => {
+                if !shell.is_posix() {
+                    return Err(Error::UnsupportedOneshell { instruction: instruction.name(), shell: shell.to_string() });
+                }
+                let status = TempFile::create("")?;
+                let output = self.run(&shell, &session(&steps, &status.path()))?;
+                let culprit = status.read()?
+                    .trim()
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|index| lines.get(index).cloned())
+                    .unwrap_or_else(|| step.command.clone());
+                Ok((step, culprit, output))
+            },
+        }
+    }
+    fn run(&self, shell: &Interpreter, command: &str) -> io::Result<Output> {
+        // with concurrent instructions the output of a step is collected
+        // and written at once so that it never interleaves with another's,
+        // when quiet it is only written if the step fails
+        if self.jobs > 1 || self.verbosity == Verbosity::Quiet {
+            shell.execute_buffered(command)
+        } else {
+            shell.execute(command)
+        }
+    }
+    fn write_output(&self, output: &Output) -> io::Result<()> {
+        if self.safe {
+            let stdout = SString::new(&output.stdout);
+            let stderr = SString::new(&output.stderr);
+            writeln!(io::stdout().lock(), "{}", stdout.soft_word())?;
+            writeln!(io::stderr().lock(), "{}", stderr.soft_word())?;
+        } else {
+            io::stdout().lock().write_all(&output.stdout)?;
+            io::stderr().lock().write_all(&output.stderr)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod baker_tests {
+    use crate::bak::{session, trace, Baker, Step};
+    use crate::pars::parse_recipe;
+    use crate::ing::{Instruction, Recipe};
+    use crate::errors::Error;
+
+    fn labels(labels: &[&str]) -> Vec<String> {
+        labels.iter().map(|l| l.to_string()).collect()
+    }
+
+    fn scratch(name: &str) -> std::path::PathBuf {
+        let cwd = std::env::temp_dir().join(format!("bakefile-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&cwd).unwrap();
+        cwd
+    }
+
+    #[test]
+    fn test_dependencies_are_performed_once_and_first() {
+        let cwd = scratch("deps");
+        let recipe = Recipe::with_instructions(vec![
+            Instruction::with_dependencies("all", &["echo all >> log"], &["frost", "bake"]),
+            Instruction::with_dependencies("frost", &["echo frost >> log"], &["bake"]),
+            Instruction::with_dependencies("bake", &["echo bake >> log"], &["mix"]),
+            Instruction::with_action("mix", "echo mix >> log"),
+        ]);
+
+        let report = Baker::new(Some(cwd.display().to_string()), false, vec!["all".to_string(), "mix".to_string()]).perform(recipe);
+
+        assert!(report.is_ok_and(|report| report.success()));
+        let log = std::fs::read_to_string(cwd.join("log")).unwrap();
+        std::fs::remove_dir_all(&cwd).unwrap();
+        assert_eq!(log, "mix\nbake\nfrost\nall\n");
+    }
+
+    #[test]
+    fn test_independent_instructions_run_in_parallel() -> Result<(), Error> {
+        let cwd = scratch("parallel");
+        // each side waits for the other to have started, which only
+        // succeeds when both run at the same time
+        let wait = |mine: &str, theirs: &str| format!(
+            "touch {}; i=0; while [ ! -f {} ] && [ $i -lt 100 ]; do sleep 0.05; i=$((i+1)); done; test -f {}",
+            mine, theirs, theirs,
+        );
+        let recipe = Recipe::with_instructions(vec![
+            Instruction::with_dependencies("all", &["echo all"], &["lint", "test"]),
+            Instruction::with_action("lint", &wait("lint", "test")),
+            Instruction::with_action("test", &wait("test", "lint")),
+        ]);
+        let order = recipe.graph().order(&labels(&["all"]))?;
+
+        let report = Baker::new(Some(cwd.display().to_string()), false, Vec::new()).with_jobs(2).bake(recipe, order)?;
+
+        std::fs::remove_dir_all(&cwd).unwrap();
+        assert!(report.success(), "{}", report);
+        assert_eq!(report.succeeded().last(), Some(&"all".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_ingredients_dont_block_other_jobs() -> Result<(), Error> {
+        let cwd = scratch("ingredient-jobs");
+        let go = cwd.join("go").display().to_string();
+        let mut recipe = Recipe::with_instructions(vec![
+            Instruction::with_dependencies("all", &["echo %[WAITED] > all"], &["slow", "go"]),
+            Instruction::with_action("slow", "echo %[WAITED] > slow"),
+            Instruction::with_action("go", "touch go"),
+        ]);
+        recipe.set_command_ingredient("WAITED", &format!(
+            "i=0; while [ ! -f {} ] && [ $i -lt 100 ]; do sleep 0.05; i=$((i+1)); done; test -f {} && echo ok",
+            go, go,
+        ));
+        let order = recipe.graph().order(&labels(&["all"]))?;
+
+        let report = Baker::new(Some(cwd.display().to_string()), false, Vec::new()).with_jobs(2).bake(recipe, order)?;
+
+        let all = std::fs::read_to_string(cwd.join("all")).unwrap_or_default();
+        std::fs::remove_dir_all(&cwd).unwrap();
+        assert!(report.success(), "{}", report);
+        assert_eq!(all, "ok\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_failure_stops_scheduling() -> Result<(), Error> {
+        let cwd = scratch("failure");
+        let recipe = Recipe::with_instructions(vec![
+            Instruction::with_dependencies("all", &["touch all"], &["broken", "slow"]),
+            Instruction::with_action("broken", "exit 4"),
+            Instruction::with_action("slow", "sleep 0.2; touch slow"),
+            Instruction::with_dependencies("after", &["touch after"], &["slow"]),
+        ]);
+        let order = recipe.graph().order(&labels(&["all", "after"]))?;
+
+        let report = Baker::new(Some(cwd.display().to_string()), false, Vec::new()).with_jobs(2).bake(recipe, order)?;
+
+        let created = ["all", "slow", "after"].iter().filter(|f| cwd.join(f).exists()).count();
+        std::fs::remove_dir_all(&cwd).unwrap();
+        assert_eq!(report.error().unwrap().to_string(), "StepFailed: step \"exit 4\" of instruction \"broken\" exited with status 4");
+        assert_eq!(created, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_going_skips_only_dependents() -> Result<(), Error> {
+        let cwd = scratch("keep-going");
+        let recipe = Recipe::with_instructions(vec![
+            Instruction::with_dependencies("all", &["touch all"], &["broken", "after"]),
+            Instruction::with_action("broken", "exit 4"),
+            Instruction::with_dependencies("package", &["touch package"], &["broken"]),
+            Instruction::with_action("slow", "sleep 0.2; touch slow"),
+            Instruction::with_dependencies("after", &["touch after"], &["slow"]),
+        ]);
+        let order = recipe.graph().order(&labels(&["broken", "package", "after", "all"]))?;
+
+        let report = Baker::new(Some(cwd.display().to_string()), false, Vec::new()).with_keep_going(true).bake(recipe, order)?;
+
+        let created = ["all", "package", "slow", "after"].iter().filter(|f| cwd.join(f).exists()).count();
+        std::fs::remove_dir_all(&cwd).unwrap();
+        assert_eq!(report.succeeded(), labels(&["slow", "after"]));
+        assert_eq!(report.skipped, labels(&["package", "all"]));
+        assert_eq!(report.to_string(), "2 succeeded, 1 failed, 2 skipped\n  failed: broken\n  skipped: package all");
+        assert_eq!(created, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_of_a_failed_instruction() -> Result<(), Error> {
+        let cwd = scratch("report");
+        let mut recipe = Recipe::with_instructions(vec![
+            Instruction::with_action("build", "echo %[WHO] > out"),
+            Instruction::with_action("build", "kill -9 $$"),
+            Instruction::with_action("build", "echo never"),
+        ]);
+        recipe.set_ingredient("WHO", "baker");
+
+        let report = Baker::new(Some(cwd.display().to_string()), false, vec!["build".to_string()]).perform(recipe)?;
+
+        std::fs::remove_dir_all(&cwd).unwrap();
+        let build = &report.instructions[0];
+        assert_eq!(build.label, "build");
+        assert_eq!(build.steps.len(), 2);
+        assert_eq!(build.steps[0].command, "echo baker > out");
+        assert_eq!((build.steps[0].code, build.steps[0].signal), (Some(0), None));
+        assert_eq!((build.steps[1].code, build.steps[1].signal), (None, Some(9)));
+        assert!(build.duration >= build.steps[0].duration + build.steps[1].duration);
+        assert_eq!(
+            report.error().unwrap().to_string(),
+            "StepFailed: step \"kill -9 $$\" of instruction \"build\" was terminated by a signal",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_shell_of_the_recipe_cli_and_instruction() -> Result<(), Error> {
+        let cwd = scratch("shell");
+        let mut strict = Instruction::with_action("strict", "false | true");
+        let mut relaxed = Instruction::with_action("relaxed", "false | true");
+        relaxed.set_shell("sh");
+        strict.add_dependency("relaxed");
+        let mut recipe = Recipe::with_instructions(vec![strict, relaxed]);
+        recipe.set_shell("bash -o pipefail -c");
+
+        let baker = Baker::new(Some(cwd.display().to_string()), false, vec!["strict".to_string()]);
+        let failed = baker.perform(recipe.clone())?;
+        let passed = baker.with_shell(Some("bash".to_string())).perform(recipe)?;
+
+        std::fs::remove_dir_all(&cwd).unwrap();
+        assert_eq!(failed.succeeded(), labels(&["relaxed"]));
+        assert_eq!(failed.failed()[0].label, "strict");
+        assert!(passed.success(), "{}", passed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shebang_instruction_runs_as_one_script() -> Result<(), Error> {
+        let cwd = scratch("shebang");
+        std::fs::create_dir_all(cwd.join("sub")).unwrap();
+        let mut recipe = parse_recipe("script:
+      #!/bin/sh -e
+      cd sub
+      # still part of the script
+      for word in %[WORDS]; do
+          echo $word >> log
+      done
+")?;
+        recipe.set_ingredient("WORDS", "flour water");
+
+        let report = Baker::new(Some(cwd.display().to_string()), false, Vec::new()).perform(recipe)?;
+
+        let log = std::fs::read_to_string(cwd.join("sub").join("log")).unwrap();
+        std::fs::remove_dir_all(&cwd).unwrap();
+        assert!(report.success(), "{}", report);
+        assert_eq!(report.instructions[0].steps.len(), 1);
+        assert!(report.instructions[0].steps[0].command.starts_with("#!/bin/sh -e\ncd sub\n"));
+        assert_eq!(log, "flour\nwater\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_oneshell_keeps_state_and_blames_the_failed_step() -> Result<(), Error> {
+        let cwd = scratch("oneshell");
+        std::fs::create_dir_all(cwd.join("sub")).unwrap();
+        let recipe = parse_recipe("[oneshell]
+session:
+      cd sub
+      export GREETING=hallo
+      echo $GREETING > log
+      false
+      touch never
+")?;
+        let everywhere = parse_recipe("set oneshell\nsteps:\n      cd sub\n      touch here\n")?;
+
+        let report = Baker::new(Some(cwd.display().to_string()), false, Vec::new()).perform(recipe)?;
+        let steps = Baker::new(Some(cwd.display().to_string()), false, Vec::new()).perform(everywhere)?;
+
+        let log = std::fs::read_to_string(cwd.join("sub").join("log")).unwrap();
+        let created = ["here", "never"].map(|f| cwd.join("sub").join(f).exists());
+        std::fs::remove_dir_all(&cwd).unwrap();
+        assert_eq!(log, "hallo\n");
+        assert_eq!(created, [true, false]);
+        assert!(steps.success(), "{}", steps);
+        assert_eq!(report.instructions[0].steps[0].command, "cd sub\nexport GREETING=hallo\necho $GREETING > log\nfalse\ntouch never");
+        assert_eq!(report.error().unwrap().to_string(), "StepFailed: step \"false\" of instruction \"session\" exited with status 1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_oneshell_needs_a_posix_shell() -> Result<(), Error> {
+        let cwd = scratch("oneshell-python");
+        let recipe = parse_recipe("[shell python3 -c]\n[oneshell]\nrun:\n      print(1)\n")?;
+        let message = "UnsupportedOneshell: instruction \"run\" is oneshell but `python3 -c` is not a POSIX shell such as sh or bash";
+
+        let report = Baker::new(Some(cwd.display().to_string()), false, Vec::new()).perform(recipe.clone())?;
+        let plan = Baker::new(Some(cwd.display().to_string()), false, Vec::new()).plan(recipe);
+        let zsh = Baker::new(Some(cwd.display().to_string()), false, Vec::new()).with_shell(Some("/bin/zsh -c".to_string()));
+        let zsh = zsh.plan(parse_recipe("set oneshell\nrun:\n      true\n")?);
+
+        std::fs::remove_dir_all(&cwd).unwrap();
+        assert_eq!(report.error().unwrap().to_string(), message);
+        assert!(report.instructions[0].steps.is_empty());
+        assert_eq!(plan.unwrap_err().to_string(), message);
+        assert!(zsh.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_session_quotes_the_status_path() -> Result<(), Error> {
+        let cwd = scratch("it's");
+        let status = cwd.join("status").display().to_string();
+
+        let output = std::process::Command::new("sh").arg("-c").arg(session(&[Step::new("true")], &status)).output()?;
+
+        let written = std::fs::read_to_string(&status).unwrap_or_default();
+        std::fs::remove_dir_all(&cwd).unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(written, "0\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefixed_steps_are_reported_and_tolerated() -> Result<(), Error> {
+        let cwd = scratch("prefixes");
+        let recipe = parse_recipe("clean:
+      @echo cleaning
+      -rm missing
+      touch cleaned
+[oneshell]
+session:
+      -false
+      touch session
+")?;
+
+        let baker = Baker::new(Some(cwd.display().to_string()), false, labels(&["clean", "session"]));
+        let report = baker.perform(recipe)?;
+
+        let created = ["cleaned", "session"].map(|f| cwd.join(f).exists());
+        std::fs::remove_dir_all(&cwd).unwrap();
+        assert!(report.success(), "{}", report);
+        assert_eq!(created, [true, true]);
+        let steps = &report.instructions[0].steps;
+        assert_eq!(steps.iter().map(|s| (s.silent, s.ignore_errors, s.success())).collect::<Vec<(bool, bool, bool)>>(), vec![
+            (true, false, true),
+            (false, true, false),
+            (false, false, true),
+        ]);
+        assert_eq!(steps[1].command, "rm missing");
+        Ok(())
+    }
+
+    #[test]
+    fn test_trace_skips_silent_steps() {
+        let steps = vec![Step::parse("@echo hidden"), Step::parse("-rm -f log"), Step::new("cd sub\nmake")];
+
+        assert_eq!(trace("clean", &steps), "[clean] rm -f log\n[clean] cd sub\n[clean] make\n");
+    }
+
+    #[test]
+    fn test_plan_runs_nothing() -> Result<(), Error> {
+        let cwd = scratch("plan");
+        let mut recipe = parse_recipe("set shell bash -c
+VERSION != echo 1.2
+all: package
+      @echo done > all
+package: build
+      tar czf app-%[VERSION].tgz build
+[shell sh]
+[oneshell]
+build:
+      mkdir build
+      -cd build
+")?;
+        recipe.add_instruction(Instruction::with_dependencies("py", &["#!/usr/bin/env python3", "print(1)"], &[]));
+
+        let plan = Baker::new(Some(cwd.display().to_string()), false, labels(&["all", "py"])).plan(recipe)?;
+
+        let created = std::fs::read_dir(&cwd).unwrap().count();
+        let canonical = std::fs::canonicalize(&cwd).unwrap().display().to_string();
+        std::fs::remove_dir_all(&cwd).unwrap();
+        assert_eq!(created, 0);
+        assert_eq!(plan.to_string(), format!("cwd: {}
+order: build package all py
+[build] sh -c (oneshell)
+      mkdir build
+      -cd build
+[package] bash -c
+      tar czf app-$(echo 1.2).tgz build
+[all] bash -c
+      @echo done > all
+[py] /usr/bin/env python3 (script)
+      #!/usr/bin/env python3
+      print(1)
+", canonical));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_target_is_an_error() -> Result<(), Error> {
+        let cwd = scratch("unknown");
+        let recipe = parse_recipe("all: test\n      touch all\ntest:\n      touch test\n")?;
+
+        let result = Baker::new(Some(cwd.display().to_string()), false, labels(&["tset"])).perform(recipe.clone());
+        let plan = Baker::new(Some(cwd.display().to_string()), false, labels(&["release"])).plan(recipe);
+
+        let created = std::fs::read_dir(&cwd).unwrap().count();
+        std::fs::remove_dir_all(&cwd).unwrap();
+        assert_eq!(created, 0);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "UnknownTarget: there is no instruction labeled \"tset\", did you mean `test`?\n  available targets: all, test",
+        );
+        assert_eq!(
+            plan.unwrap_err().to_string(),
+            "UnknownTarget: there is no instruction labeled \"release\"\n  available targets: all, test",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_leaves_command_ingredients_alone() -> Result<(), Error> {
+        let cwd = scratch("plan-ingredients");
+        let marker = cwd.join("evaluated");
+        let recipe = parse_recipe(&format!("STAMP != touch {} && date +%s
+all:
+      echo %[STAMP]
+", marker.display()))?;
+
+        let plan = Baker::new(Some(cwd.display().to_string()), false, labels(&["all"])).plan(recipe)?;
+
+        let evaluated = marker.exists();
+        std::fs::remove_dir_all(&cwd).unwrap();
+        assert!(!evaluated);
+        assert_eq!(plan.instructions[0].steps[0].command, format!("echo $(touch {} && date +%s)", marker.display()));
+        Ok(())
     }
 }
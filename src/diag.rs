@@ -0,0 +1,220 @@
+use serde::{Serialize, Deserialize};
+use crate::lex::Span;
+
+/// A problem found in a Bakefile, with enough context to point at it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub message: String,
+    pub path: Option<String>,
+    pub span: Span,
+    pub source_line: String,
+    pub hint: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(message: &str, span: Span) -> Diagnostic {
+        Diagnostic {
+            message: message.to_string(),
+            path: None,
+            span,
+            source_line: String::new(),
+            hint: None,
+        }
+    }
+    pub fn with_hint(mut self, hint: &str) -> Diagnostic {
+        self.hint = Some(hint.to_string());
+        self
+    }
+    pub fn with_path(mut self, path: &str) -> Diagnostic {
+        self.path = Some(path.to_string());
+        self
+    }
+    /// Fills in the offending line from the full text of the Bakefile.
+    pub fn with_source(mut self, source: &str) -> Diagnostic {
+        if let Some(line) = source.lines().nth(self.span.line.saturating_sub(1)) {
+            self.source_line = line.strip_suffix('\r').unwrap_or(line).to_string();
+        }
+        self
+    }
+    pub fn line(&self) -> usize {
+        self.span.line
+    }
+    pub fn column(&self) -> usize {
+        self.span.column
+    }
+    pub fn location(&self) -> String {
+        match &self.path {
+            Some(path) => format!("{}:{}", path, self.span),
+            None => format!("{}", self.span),
+        }
+    }
+    /// Renders the diagnostic as a snippet of the offending line with a
+    /// caret under the bad column, optionally with ANSI colors.
+    pub fn render(&self, color: bool) -> String {
+        let (bold_red, blue, reset) = if color {
+            ("\x1b[1;31m", "\x1b[1;34m", "\x1b[0m")
+        } else {
+            ("", "", "")
+        };
+        let lineno = self.line().to_string();
+        let gutter = " ".repeat(lineno.len());
+        let mut out = format!("{}error{}: {}\n", bold_red, reset, self.message);
+        out.push_str(&format!("{}{}-->{} {}\n", gutter, blue, reset, self.location()));
+        if !self.source_line.is_empty() {
+            let padding = self.source_line
+                .chars()
+                .take(self.column().saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect::<String>();
+            let width = self.caret_width();
+            out.push_str(&format!("{} {}|{}\n", gutter, blue, reset));
+            out.push_str(&format!("{}{} |{} {}\n", blue, lineno, reset, self.source_line));
+            out.push_str(&format!("{} {}|{} {}{}{}{}\n", gutter, blue, reset, padding, bold_red, "^".repeat(width), reset));
+        }
+        if let Some(hint) = &self.hint {
+            out.push_str(&format!("{} {}={} hint: {}\n", gutter, blue, reset, hint));
+        }
+        out.trim_end().to_string()
+    }
+    fn caret_width(&self) -> usize {
+        let line = &self.source_line;
+        let start = line.char_indices().nth(self.column().saturating_sub(1)).map(|(i, _)| i).unwrap_or(line.len());
+        let mut end = (start + self.span.end.saturating_sub(self.span.start)).min(line.len());
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        line[start..end].chars().count().max(1)
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.render(false))
+    }
+}
+
+
+/// Every diagnostic found while reading a Bakefile, in source order.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    pub fn new() -> Diagnostics {
+        Diagnostics(Vec::new())
+    }
+    pub fn push(&mut self, diagnostic: Diagnostic) {
+        self.0.push(diagnostic);
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub fn iter(&self) -> std::slice::Iter<'_, Diagnostic> {
+        self.0.iter()
+    }
+    pub fn sort(&mut self) {
+        self.0.sort_by_key(|diagnostic| diagnostic.span.start);
+    }
+    pub fn with_source(self, source: &str) -> Diagnostics {
+        Diagnostics(self.0.into_iter().map(|d| d.with_source(source)).collect())
+    }
+    pub fn with_path(self, path: &str) -> Diagnostics {
+        Diagnostics(self.0.into_iter().map(|d| d.with_path(path)).collect())
+    }
+    pub fn summary(&self) -> String {
+        let count = match self.len() {
+            1 => "1 error".to_string(),
+            n => format!("{} errors", n),
+        };
+        match self.0.first().and_then(|d| d.path.as_ref()) {
+            Some(path) => format!("could not parse {} due to {}", path, count),
+            None => format!("could not parse recipe due to {}", count),
+        }
+    }
+    pub fn render(&self, color: bool) -> String {
+        let mut out = self.iter().map(|d| d.render(color)).collect::<Vec<String>>().join("\n\n");
+        let (bold_red, reset) = if color { ("\x1b[1;31m", "\x1b[0m") } else { ("", "") };
+        out.push_str(&format!("\n\n{}error{}: {}", bold_red, reset, self.summary()));
+        out
+    }
+}
+
+impl From<Diagnostic> for Diagnostics {
+    fn from(diagnostic: Diagnostic) -> Diagnostics {
+        Diagnostics(vec![diagnostic])
+    }
+}
+
+impl IntoIterator for Diagnostics {
+    type Item = Diagnostic;
+    type IntoIter = std::vec::IntoIter<Diagnostic>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl std::fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.render(false))
+    }
+}
+
+#[cfg(test)]
+mod diag_tests {
+    use crate::diag::Diagnostic;
+    use crate::lex::Span;
+    use k9::assert_equal;
+
+    #[test]
+    fn test_location_with_path() {
+        let diagnostic = Diagnostic::new("bad", Span::new(8, 11, 2, 3)).with_path("Bakefile");
+
+        assert_equal!(diagnostic.location(), "Bakefile:2:3");
+        assert_equal!(Diagnostic::new("bad", Span::new(8, 11, 2, 3)).location(), "2:3");
+    }
+
+    #[test]
+    fn test_caret_width_multi_character() {
+        let diagnostic = Diagnostic::new("bad", Span::new(4, 7, 1, 5)).with_source("foo bar baz");
+
+        assert_equal!(diagnostic.caret_width(), 3);
+    }
+
+    #[test]
+    fn test_caret_width_non_ascii() {
+        let diagnostic = Diagnostic::new("bad", Span::new(5, 11, 1, 6)).with_source("echo hallö");
+
+        assert_equal!(diagnostic.caret_width(), 5);
+    }
+
+    #[test]
+    fn test_caret_width_past_end_of_line() {
+        let diagnostic = Diagnostic::new("bad", Span::new(3, 4, 1, 4)).with_source("foo");
+
+        assert_equal!(diagnostic.caret_width(), 1);
+    }
+
+    #[test]
+    fn test_with_source_last_line() {
+        let diagnostic = Diagnostic::new("bad", Span::new(5, 6, 2, 1));
+
+        assert_equal!(diagnostic.clone().with_source("foo:\nbar").source_line, "bar");
+        assert_equal!(diagnostic.with_source("foo:\r\nbar\r\n").source_line, "bar");
+    }
+
+    #[test]
+    fn test_render_color() {
+        let diagnostic = Diagnostic::new("bad step", Span::new(5, 8, 2, 1))
+            .with_source("foo:\nbar\n")
+            .with_hint("indent it");
+
+        assert_equal!(diagnostic.render(true), "\x1b[1;31merror\x1b[0m: bad step
+ \x1b[1;34m-->\x1b[0m 2:1
+  \x1b[1;34m|\x1b[0m
+\x1b[1;34m2 |\x1b[0m bar
+  \x1b[1;34m|\x1b[0m \x1b[1;31m^^^\x1b[0m
+  \x1b[1;34m=\x1b[0m hint: indent it");
+    }
+}
@@ -1,13 +1,56 @@
+use crate::diag::{Diagnostic, Diagnostics};
+use crate::graph::DependencyEdge;
+
 #[derive(Debug)]
 pub enum Error {
-    RecipeParsingError(String),
+    RecipeParsingError(Diagnostics),
     UnstructedRecipe(String),
+    UndefinedIngredient {
+        name: String,
+        instruction: String,
+        step: String,
+    },
+    RequiredIngredient {
+        name: String,
+        message: String,
+        instruction: String,
+        step: String,
+    },
+    IngredientCycle(Vec<String>),
+    InvalidOverride(String),
+    IngredientCommandFailed {
+        name: String,
+        command: String,
+        code: Option<i32>,
+    },
+    DependencyCycle(Vec<DependencyEdge>),
+    UnknownDependency(DependencyEdge),
+    UnknownInstruction(String),
+    UnknownTarget {
+        label: String,
+        targets: Vec<String>,
+        suggestions: Vec<String>,
+    },
+    StepFailed {
+        instruction: String,
+        step: String,
+        code: Option<i32>,
+    },
+    UnsupportedOneshell {
+        instruction: String,
+        shell: String,
+    },
     IOError(std::io::Error),
 }
 impl std::error::Error for Error {}
 
-impl From<String> for Error {
-    fn from(e: String) -> Self {
+impl From<Diagnostic> for Error {
+    fn from(e: Diagnostic) -> Self {
+        Error::RecipeParsingError(Diagnostics::from(e))
+    }
+}
+impl From<Diagnostics> for Error {
+    fn from(e: Diagnostics) -> Self {
         Error::RecipeParsingError(e)
     }
 }
@@ -20,8 +63,40 @@ impl From<std::io::Error> for Error {
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Error::RecipeParsingError(e) => write!(f, "RecipeParsingError: {}", e),
+            Error::RecipeParsingError(e) => write!(f, "{}", e),
             Error::UnstructedRecipe(e) => write!(f, "UnstructedRecipe: {}", e),
+            Error::UndefinedIngredient { name, instruction, step } => write!(f, "UndefinedIngredient: %[{}] is not defined (instruction {:?}, step {:?})", name, instruction, step),
+            Error::RequiredIngredient { name, message, instruction, step } => write!(f, "RequiredIngredient: %[{}] {} (instruction {:?}, step {:?})", name, message, instruction, step),
+            Error::IngredientCycle(cycle) => write!(f, "IngredientCycle: {}", cycle.join(" -> ")),
+            Error::InvalidOverride(arg) => write!(f, "InvalidOverride: expected NAME=value, got {:?}", arg),
+            Error::IngredientCommandFailed { name, command, code } => match code {
+                Some(code) => write!(f, "IngredientCommandFailed: %[{}] command {:?} exited with status {}", name, command, code),
+                None => write!(f, "IngredientCommandFailed: %[{}] command {:?} was terminated by a signal", name, command),
+            },
+            Error::DependencyCycle(edges) => {
+                let mut cycle = edges.iter().map(|edge| edge.from.clone()).collect::<Vec<String>>();
+                cycle.extend(edges.last().map(|edge| edge.to.clone()));
+                write!(f, "DependencyCycle: {}", cycle.join(" -> "))?;
+                for edge in edges {
+                    write!(f, "\n  {}", edge)?;
+                }
+                Ok(())
+            },
+            Error::UnknownDependency(edge) => write!(f, "UnknownDependency: {} but there is no instruction labeled {:?}", edge, edge.to),
+            Error::UnknownInstruction(label) => write!(f, "UnknownInstruction: there is no instruction labeled {:?}", label),
+            Error::UnknownTarget { label, targets, suggestions } => {
+                write!(f, "UnknownTarget: there is no instruction labeled {:?}", label)?;
+                if !suggestions.is_empty() {
+                    let suggestions = suggestions.iter().map(|s| format!("`{}`", s)).collect::<Vec<String>>();
+                    write!(f, ", did you mean {}?", suggestions.join(" or "))?;
+                }
+                write!(f, "\n  available targets: {}", targets.join(", "))
+            },
+            Error::StepFailed { instruction, step, code } => match code {
+                Some(code) => write!(f, "StepFailed: step {:?} of instruction {:?} exited with status {}", step, instruction, code),
+                None => write!(f, "StepFailed: step {:?} of instruction {:?} was terminated by a signal", step, instruction),
+            },
+            Error::UnsupportedOneshell { instruction, shell } => write!(f, "UnsupportedOneshell: instruction {:?} is oneshell but `{}` is not a POSIX shell such as sh or bash", instruction, shell),
             Error::IOError(e) => write!(f, "IOError: {}", e),
         }
     }
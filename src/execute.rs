@@ -1,4 +1,9 @@
 pub use std::process::{Command, Output, Stdio};
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub trait Shell<'a> {
     fn new(cwd: Option<String>) -> Self
@@ -15,13 +20,35 @@ pub trait Shell<'a> {
     fn execute(&self, shell_command: &str) -> Result<Output, std::io::Error> {
         let mut args = Vec::new();
         args.extend(self.exec_params());
-        args.push(format!("{}", shell_command));
-        let mut cmd = Command::new(&self.command());
-        Ok(cmd
+        args.push(shell_command.to_string());
+        let mut cmd = Command::new(self.command());
+        cmd
             .current_dir(self.get_cwd())
             .args(args)
             .spawn()?
-            .wait_with_output()?)
+            .wait_with_output()
+    }
+    /// Runs `shell_command` collecting both its stdout and stderr so that
+    /// they can be written out in one go.
+    fn execute_buffered(&self, shell_command: &str) -> Result<Output, std::io::Error> {
+        Command::new(self.command())
+            .current_dir(self.get_cwd())
+            .args(self.exec_params())
+            .arg(shell_command)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?
+            .wait_with_output()
+    }
+    /// Runs `shell_command` collecting its stdout instead of passing it on.
+    fn capture(&self, shell_command: &str) -> Result<Output, std::io::Error> {
+        Command::new(self.command())
+            .current_dir(self.get_cwd())
+            .args(self.exec_params())
+            .arg(shell_command)
+            .stdout(Stdio::piped())
+            .spawn()?
+            .wait_with_output()
     }
 }
 
@@ -32,10 +59,10 @@ pub struct Bash {
 
 impl Shell<'_> for Bash {
     fn new(cwd: Option<String>) -> Bash {
-        Bash { cwd: cwd }
+        Bash { cwd }
     }
     fn get_path(&self) -> String {
-        format!("bash")
+        "bash".to_string()
     }
     fn get_cwd(&self) -> String {
         match &self.cwd {
@@ -52,10 +79,91 @@ pub struct Sh {
 
 impl Shell<'_> for Sh {
     fn new(cwd: Option<String>) -> Sh {
-        Sh { cwd: cwd }
+        Sh { cwd }
+    }
+    fn get_path(&self) -> String {
+        "sh".to_string()
+    }
+    fn get_cwd(&self) -> String {
+        match &self.cwd {
+            Some(cwd) => cwd.to_string(),
+            None => ".".to_string(),
+        }
+    }
+}
+
+const POSIX_SHELLS: &[&str] = &["sh", "bash", "dash", "ash", "ksh", "mksh", "zsh", "yash", "posh"];
+
+/// A shell given as a program followed by the arguments that go before the
+/// command, such as `bash -euo pipefail -c`. A program on its own, like
+/// `zsh`, gets `-c`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interpreter {
+    program: String,
+    arguments: Vec<String>,
+    cwd: Option<String>,
+}
+
+impl Interpreter {
+    pub fn parse(spec: &str, cwd: Option<String>) -> Interpreter {
+        let mut words = spec.split_whitespace().map(|word| word.to_string());
+        let Some(program) = words.next() else {
+            return Interpreter::new(cwd);
+        };
+        let mut arguments = words.collect::<Vec<String>>();
+        if arguments.is_empty() {
+            arguments.push("-c".to_string());
+        }
+        Interpreter { program, arguments, cwd }
+    }
+}
+
+impl Interpreter {
+    /// Whether the program is a shell that understands POSIX sh, which a
+    /// oneshell instruction is written in.
+    pub fn is_posix(&self) -> bool {
+        let name = self.program.rsplit('/').next().unwrap_or_default();
+        POSIX_SHELLS.contains(&name)
+    }
+    /// The interpreter named by a `#!` line. Like the kernel does, what
+    /// follows the program is passed to it as a single argument.
+    pub fn shebang(line: &str, cwd: Option<String>) -> Option<Interpreter> {
+        let line = line.strip_prefix("#!")?.trim();
+        let (program, argument) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        if program.is_empty() {
+            return None;
+        }
+        Some(Interpreter {
+            program: program.to_string(),
+            arguments: Some(argument.trim()).filter(|a| !a.is_empty()).map(|a| a.to_string()).into_iter().collect(),
+            cwd,
+        })
+    }
+}
+
+impl std::fmt::Display for Interpreter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.program)?;
+        for argument in &self.arguments {
+            write!(f, " {}", argument)?;
+        }
+        Ok(())
+    }
+}
+
+impl Shell<'_> for Interpreter {
+    fn new(cwd: Option<String>) -> Interpreter {
+        Interpreter {
+            program: "sh".to_string(),
+            arguments: vec!["-c".to_string()],
+            cwd,
+        }
     }
     fn get_path(&self) -> String {
-        format!("sh")
+        self.program.clone()
+    }
+    fn exec_params(&self) -> Vec<String> {
+        self.arguments.clone()
     }
     fn get_cwd(&self) -> String {
         match &self.cwd {
@@ -64,3 +172,78 @@ impl Shell<'_> for Sh {
         }
     }
 }
+
+/// A temporary file, such as the body of a `#!` instruction, which is
+/// removed again once dropped. It is only readable by its owner and can be
+/// executed by them.
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Creates the file under a fresh name, never following an existing file
+    /// or link that happens to have that name, and with its permissions set
+    /// before anything is written to it.
+    pub fn create(contents: &str) -> Result<TempFile, std::io::Error> {
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+        loop {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+            let name = format!("bakefile-{}-{}-{:08x}", std::process::id(), CREATED.fetch_add(1, Ordering::Relaxed), nanos);
+            let path = std::env::temp_dir().join(name);
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o700);
+            }
+            match options.open(&path) {
+                Ok(mut handle) => {
+                    let file = TempFile { path };
+                    handle.write_all(contents.as_bytes())?;
+                    return Ok(file);
+                }
+                Err(error) if error.kind() == ErrorKind::AlreadyExists => continue,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+    pub fn path(&self) -> String {
+        self.path.display().to_string()
+    }
+    pub fn read(&self) -> Result<String, std::io::Error> {
+        std::fs::read_to_string(&self.path)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod temp_file_tests {
+    use crate::execute::TempFile;
+    use crate::errors::Error;
+
+    #[test]
+    fn test_temp_files_are_fresh_and_private() -> Result<(), Error> {
+        let first = TempFile::create("echo one\n")?;
+        let second = TempFile::create("echo two\n")?;
+        let path = first.path();
+
+        assert_ne!(first.path(), second.path());
+        assert_eq!(first.read()?, "echo one\n");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path)?.permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+        drop(first);
+        assert!(!std::path::Path::new(&path).exists());
+        Ok(())
+    }
+}
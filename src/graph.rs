@@ -0,0 +1,228 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use crate::errors::Error;
+use crate::ing::Recipe;
+use crate::lex::Span;
+
+/// One `from: to` edge of the dependency graph and where it was declared.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DependencyEdge {
+    pub from: String,
+    pub to: String,
+    pub span: Option<Span>,
+}
+
+impl std::fmt::Display for DependencyEdge {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.span {
+            Some(span) => write!(f, "{} -> {} declared at {}", self.from, self.to, span),
+            None => write!(f, "{} -> {}", self.from, self.to),
+        }
+    }
+}
+
+/// The instructions of a recipe and what each of them depends on. This is
+/// what decides the order in which instructions are performed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DependencyGraph {
+    labels: Vec<String>,
+    edges: BTreeMap<String, Vec<DependencyEdge>>,
+}
+
+impl DependencyGraph {
+    pub fn from_recipe(recipe: &Recipe) -> DependencyGraph {
+        let mut labels = Vec::<String>::new();
+        let mut edges = BTreeMap::<String, Vec<DependencyEdge>>::new();
+        for label in recipe.labels() {
+            let mut from = Vec::<DependencyEdge>::new();
+            for instruction in recipe.get_instructions(&label) {
+                for dependency in instruction.dependencies() {
+                    if from.iter().any(|edge| edge.to == dependency) {
+                        continue;
+                    }
+                    from.push(DependencyEdge {
+                        from: label.clone(),
+                        span: instruction.dependency_span(&dependency),
+                        to: dependency,
+                    });
+                }
+            }
+            labels.push(label.clone());
+            edges.insert(label, from);
+        }
+        DependencyGraph { labels, edges }
+    }
+    pub fn contains(&self, label: &str) -> bool {
+        self.edges.contains_key(label)
+    }
+    pub fn labels(&self) -> Vec<String> {
+        self.labels.clone()
+    }
+    pub fn edges(&self, label: &str) -> Vec<DependencyEdge> {
+        self.edges.get(label).cloned().unwrap_or_default()
+    }
+    pub fn dependencies(&self, label: &str) -> Vec<String> {
+        self.edges(label).into_iter().map(|edge| edge.to).collect()
+    }
+    pub fn dependents(&self, label: &str) -> Vec<String> {
+        self.labels
+            .iter()
+            .filter(|from| self.edges[*from].iter().any(|edge| edge.to == label))
+            .cloned()
+            .collect()
+    }
+    /// The labels within a few edits of `label`, closest first, to suggest
+    /// in place of a mistyped one.
+    pub fn suggestions(&self, label: &str) -> Vec<String> {
+        let limit = (label.chars().count() / 3).max(1);
+        let mut close = self.labels
+            .iter()
+            .map(|candidate| (edit_distance(label, candidate), candidate))
+            .filter(|(distance, _)| *distance <= limit)
+            .collect::<Vec<(usize, &String)>>();
+        close.sort_by_key(|(distance, _)| *distance);
+        close.into_iter().map(|(_, candidate)| candidate.clone()).collect()
+    }
+    /// Every instruction needed to perform `roots`, each listed once and
+    /// after all of its dependencies. Dependencies are visited in the order
+    /// they are declared so the result is stable.
+    pub fn order(&self, roots: &[String]) -> Result<Vec<String>, Error> {
+        let mut order = Vec::<String>::new();
+        let mut done = BTreeSet::<String>::new();
+        for root in roots {
+            if !self.contains(root) {
+                return Err(Error::UnknownInstruction(root.clone()));
+            }
+            self.visit(root, &mut Vec::new(), &mut done, &mut order)?;
+        }
+        Ok(order)
+    }
+    fn visit(&self, label: &str, visiting: &mut Vec<String>, done: &mut BTreeSet<String>, order: &mut Vec<String>) -> Result<(), Error> {
+        if done.contains(label) {
+            return Ok(());
+        }
+        if let Some(position) = visiting.iter().position(|l| l == label) {
+            let mut cycle = visiting[position..].to_vec();
+            cycle.push(label.to_string());
+            return Err(Error::DependencyCycle(self.path(&cycle)));
+        }
+        visiting.push(label.to_string());
+        for edge in &self.edges[label] {
+            if !self.contains(&edge.to) {
+                return Err(Error::UnknownDependency(edge.clone()));
+            }
+            self.visit(&edge.to, visiting, done, order)?;
+        }
+        visiting.pop();
+        done.insert(label.to_string());
+        order.push(label.to_string());
+        Ok(())
+    }
+    fn path(&self, labels: &[String]) -> Vec<DependencyEdge> {
+        labels.windows(2).map(|pair| {
+            self.edges(&pair[0])
+                .into_iter()
+                .find(|edge| edge.to == pair[1])
+                .unwrap_or(DependencyEdge { from: pair[0].clone(), to: pair[1].clone(), span: None })
+        }).collect()
+    }
+}
+
+/// How many characters have to be inserted, removed, replaced or swapped
+/// with their neighbour to turn `a` into `b`, so that `tset` is one edit away
+/// from `test`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<char>>();
+    let b = b.chars().collect::<Vec<char>>();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1).min(d[i][j - 1] + 1).min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod graph_tests {
+    use crate::graph::{edit_distance, DependencyEdge};
+    use crate::ing::{Instruction, Recipe};
+    use crate::lex::Span;
+    use crate::pars::parse_recipe;
+    use crate::errors::Error;
+
+    fn labels(labels: &[&str]) -> Vec<String> {
+        labels.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn test_diamond_is_deduplicated() -> Result<(), Error> {
+        let recipe = Recipe::with_instructions(vec![
+            Instruction::with_dependencies("all", &[], &["x", "y"]),
+            Instruction::with_dependencies("x", &[], &["base"]),
+            Instruction::with_dependencies("y", &[], &["base"]),
+            Instruction::with_action("base", "echo base"),
+        ]);
+        let graph = recipe.graph();
+
+        assert_eq!(graph.order(&labels(&["all"]))?, labels(&["base", "x", "y", "all"]));
+        assert_eq!(graph.order(&labels(&["y", "x", "all"]))?, labels(&["base", "y", "x", "all"]));
+        assert_eq!(graph.dependents("base"), labels(&["x", "y"]));
+        assert_eq!(recipe.resolve_dependencies("all")?, Some(labels(&["base", "x", "y", "all"])));
+        Ok(())
+    }
+
+    #[test]
+    fn test_repeated_instructions_are_merged() -> Result<(), Error> {
+        let recipe = parse_recipe("all: b a\nall: c a\na:\nb:\nc:\n")?;
+
+        assert_eq!(recipe.graph().dependencies("all"), labels(&["b", "a", "c"]));
+        assert_eq!(recipe.graph().order(&labels(&["all"]))?, labels(&["b", "a", "c", "all"]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_dependency() -> Result<(), Error> {
+        let recipe = parse_recipe("all: build tset\nbuild:\n")?;
+
+        match recipe.graph().order(&labels(&["all"])) {
+            Err(Error::UnknownDependency(edge)) => assert_eq!(edge, DependencyEdge {
+                from: "all".to_string(),
+                to: "tset".to_string(),
+                span: Some(Span::new(11, 15, 1, 12)),
+            }),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(recipe.graph().order(&labels(&["build"]))?, labels(&["build"]));
+        assert_eq!(
+            recipe.graph().order(&labels(&["test"])).unwrap_err().to_string(),
+            "UnknownInstruction: there is no instruction labeled \"test\"",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_suggestions() -> Result<(), Error> {
+        let recipe = parse_recipe("test:\ntests:\nbuild:\ndeploy-staging:\n")?;
+        let graph = recipe.graph();
+
+        assert_eq!(edit_distance("tset", "test"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(graph.suggestions("tset"), labels(&["test"]));
+        assert_eq!(graph.suggestions("tests"), labels(&["tests", "test"]));
+        assert_eq!(graph.suggestions("biuld"), labels(&["build"]));
+        assert_eq!(graph.suggestions("deploy-stagign"), labels(&["deploy-staging"]));
+        assert!(graph.suggestions("release").is_empty());
+        Ok(())
+    }
+}
@@ -1,14 +1,81 @@
 use serde::{Serialize, Deserialize};
 use std::collections::BTreeMap;
-use std::collections::BTreeSet;
 use crate::errors::Error;
+use crate::execute::{Sh, Shell};
+use crate::graph::DependencyGraph;
+use crate::lex::Span;
 
 
+/// A step of an instruction and the make-like prefixes it was written
+/// with: `@` keeps it from being echoed and `-` ignores a non-zero exit.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    pub command: String,
+    #[serde(default)]
+    pub silent: bool,
+    #[serde(default)]
+    pub ignore_errors: bool,
+}
+
+impl Step {
+    pub fn new(command: &str) -> Step {
+        Step {
+            command: command.to_string(),
+            silent: false,
+            ignore_errors: false,
+        }
+    }
+    /// Takes the `@` and `-` prefixes, in any order, off the front of `line`.
+    pub fn parse(line: &str) -> Step {
+        let mut step = Step::new(line);
+        let mut rest = line;
+        loop {
+            if let Some(r) = rest.strip_prefix('@') {
+                step.silent = true;
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix('-') {
+                step.ignore_errors = true;
+                rest = r;
+            } else {
+                break;
+            }
+        }
+        step.command = rest.trim_start().to_string();
+        step
+    }
+}
+
+impl std::fmt::Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.silent {
+            write!(f, "@")?;
+        }
+        if self.ignore_errors {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", self.command)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Instruction {
     label: String,
-    actions: Vec<String>,
+    actions: Vec<Step>,
     deps: Vec<String>,
+    #[serde(default)]
+    sources: BTreeMap<String, Span>,
+    #[serde(default)]
+    shell: Option<String>,
+    #[serde(default)]
+    oneshell: bool,
+}
+
+// Where a dependency was declared is only there to point at it in errors,
+// two instructions with the same label, steps and dependencies are equal.
+impl PartialEq for Instruction {
+    fn eq(&self, other: &Instruction) -> bool {
+        self.label == other.label && self.actions == other.actions && self.deps == other.deps && self.shell == other.shell && self.oneshell == other.oneshell
+    }
 }
 
 impl Instruction {
@@ -19,29 +86,47 @@ impl Instruction {
         self.label.clone()
     }
     pub fn set_action(&mut self, actions: &[&str]) {
-        self.actions.extend(actions.iter().map(|a| a.to_string() ).collect::<Vec<String>>());
+        for action in actions {
+            self.add_action(action);
+        }
     }
+    /// Adds a step, taking its `@` and `-` prefixes into account unless it
+    /// is a line of a `#!` script.
     pub fn add_action(&mut self, action: &str) {
-        self.actions.push(action.to_string());
+        let step = if self.is_script() { Step::new(action) } else { Step::parse(action) };
+        self.actions.push(step);
+    }
+    pub fn add_step(&mut self, step: Step) {
+        self.actions.push(step);
     }
     pub fn command(&self) -> String {
-        self.actions.join("\n")
+        self.steps().join("\n")
     }
+    /// The commands of the steps, without their prefixes.
     pub fn steps(&self) -> Vec<String> {
+        self.actions.iter().map(|step| step.command.clone()).collect()
+    }
+    pub fn actions(&self) -> Vec<Step> {
         self.actions.clone()
     }
+    /// Whether the first step is a `#!` line, in which case the steps are
+    /// the lines of a script that runs as a whole.
+    pub fn is_script(&self) -> bool {
+        self.actions.first().is_some_and(|step| step.command.starts_with("#!"))
+    }
     pub fn with_dependencies(name: &str, actions: &[&str], dependencies: &[&str]) -> Instruction {
-        Instruction {
-            label: name.to_string(),
-            actions: actions.iter().map(|a| a.to_string() ).collect::<Vec<String>>(),
-            deps: dependencies.iter().map(|d| d.to_string() ).collect::<Vec<String>>()
-        }
+        let mut instruction = Instruction::of_dependencies(name, dependencies);
+        instruction.set_action(actions);
+        instruction
     }
     pub fn of_dependencies(name: &str, dependencies: &[&str]) -> Instruction {
         Instruction {
             label: name.to_string(),
             actions: Vec::new(),
-            deps: dependencies.iter().map(|d| d.to_string() ).collect::<Vec<String>>()
+            deps: dependencies.iter().map(|d| d.to_string() ).collect::<Vec<String>>(),
+            sources: BTreeMap::new(),
+            shell: None,
+            oneshell: false,
         }
     }
 
@@ -49,15 +134,16 @@ impl Instruction {
         Instruction {
             label: name.to_string(),
             actions: Vec::new(),
-            deps: Vec::new()
+            deps: Vec::new(),
+            sources: BTreeMap::new(),
+            shell: None,
+            oneshell: false,
         }
     }
     pub fn with_action(name: &str, action: &str) -> Instruction {
-        Instruction {
-            label: name.to_string(),
-            actions: vec![action.to_string()],
-            deps: Vec::new()
-        }
+        let mut instruction = Instruction::new(name);
+        instruction.add_action(action);
+        instruction
     }
 
     pub fn dependencies(&self) -> Vec<String> {
@@ -67,6 +153,31 @@ impl Instruction {
     pub fn add_dependency(&mut self, dependency_name: &str) {
         self.deps.push(dependency_name.to_string());
     }
+
+    pub fn add_dependency_at(&mut self, dependency_name: &str, span: Span) {
+        self.add_dependency(dependency_name);
+        self.sources.entry(dependency_name.to_string()).or_insert(span);
+    }
+
+    pub fn dependency_span(&self, dependency_name: &str) -> Option<Span> {
+        self.sources.get(dependency_name).copied()
+    }
+    /// The shell that runs the steps of this instruction, when it doesn't
+    /// use the one of the recipe.
+    pub fn shell(&self) -> Option<String> {
+        self.shell.clone()
+    }
+    pub fn set_shell(&mut self, shell: &str) {
+        self.shell = Some(shell.to_string());
+    }
+    /// Whether all steps run as one script by a single shell, so that a
+    /// `cd` or `export` carries over to the next step.
+    pub fn oneshell(&self) -> bool {
+        self.oneshell
+    }
+    pub fn set_oneshell(&mut self, oneshell: bool) {
+        self.oneshell = oneshell;
+    }
 }
 
 
@@ -86,7 +197,7 @@ mod instruction_tests {
         let mut bake_with_frosting = Instruction::with_dependencies(
             "produce-cake",
             &["apply-frosting"],
-            &vec!["acquire-ingredients", "bake-cake"]
+            &["acquire-ingredients", "bake-cake"]
         );
         assert_eq!(&bake_with_frosting.name(), "produce-cake");
         assert_eq!(&bake_with_frosting.command(), "apply-frosting");
@@ -103,16 +214,64 @@ mod instruction_tests {
             "wait-til-cooldown".to_string(),
         ]);
     }
+
+    #[test]
+    fn test_step_prefixes() {
+        let mut instruction = Instruction::with_dependencies("quiet", &["@echo hi", "-rm gone", "-@ false", "echo -n"], &[]);
+        instruction.add_action("@-true");
+
+        assert_eq!(instruction.steps(), vec!["echo hi", "rm gone", "false", "echo -n", "true"]);
+        assert_eq!(instruction.actions().iter().map(|step| (step.silent, step.ignore_errors)).collect::<Vec<(bool, bool)>>(), vec![
+            (true, false),
+            (false, true),
+            (true, true),
+            (false, false),
+            (true, true),
+        ]);
+        assert_eq!(instruction.actions()[2].to_string(), "@-false");
+
+        let script = Instruction::with_dependencies("py", &["#!/usr/bin/env python3", "-1 if x else 2"], &[]);
+        assert_eq!(script.steps(), vec!["#!/usr/bin/env python3", "-1 if x else 2"]);
+    }
+}
+
+
+/// The value of an ingredient: either a literal substance or a shell
+/// command whose trimmed stdout becomes the value when a step first needs it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Ingredient {
+    Substance(String),
+    Command(String),
 }
 
+impl Ingredient {
+    pub fn text(&self) -> String {
+        match self {
+            Ingredient::Substance(text) | Ingredient::Command(text) => text.clone(),
+        }
+    }
+    fn push_str(&mut self, text: &str) {
+        match self {
+            Ingredient::Substance(value) | Ingredient::Command(value) => value.push_str(text),
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Recipe {
     path: Option<String>,
     inst: BTreeMap<String, Vec<Instruction>>,
-    order: BTreeSet<String>,
-    ings: BTreeMap<String, String>,
+    order: Vec<String>,
+    ings: BTreeMap<String, Ingredient>,
+    #[serde(skip)]
+    baked: BTreeMap<String, String>,
     requ: Vec<String>,
+    #[serde(default)]
+    shell: Option<String>,
+    #[serde(default)]
+    oneshell: bool,
+    #[serde(default)]
+    default: Option<String>,
 }
 impl std::fmt::Display for Recipe {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -121,7 +280,7 @@ impl std::fmt::Display for Recipe {
                 write!(f, "Recipe({})", path)
             },
             None => {
-                write!(f, "Recipe[{}]", self.order.iter().map(|k|k.to_string()).collect::<Vec<String>>().join(", "))
+                write!(f, "Recipe[{}]", self.order.join(", "))
             }
         }
     }
@@ -131,33 +290,23 @@ impl Recipe {
         Recipe {
             path: None,
             inst: BTreeMap::new(),
-            order: BTreeSet::new(),
+            order: Vec::new(),
             ings: BTreeMap::new(),
+            baked: BTreeMap::new(),
             requ: Vec::new(),
+            shell: None,
+            oneshell: false,
+            default: None,
         }
     }
-    pub fn resolve_dependencies(&self, instruction_label: &str) -> Option<Vec<String>> {
-        let mut instruction_labels = Vec::<String>::new();
-        match self.inst.get(instruction_label) {
-            None => return None,
-            Some(instructions) => {
-                for inst in instructions {
-                    let deps = inst.dependencies();
-                    if deps.len() > 0 {
-                        for dep in deps {
-                            match self.resolve_dependencies(&dep) {
-                                Some(labels) =>{
-                                    instruction_labels.extend(labels);
-                                },
-                                None => {}
-                            }
-                        }
-                    }
-                    instruction_labels.push(inst.name());
-                }
-            }
-        };
-        Some(instruction_labels)
+    pub fn resolve_dependencies(&self, instruction_label: &str) -> Result<Option<Vec<String>>, Error> {
+        if !self.inst.contains_key(instruction_label) {
+            return Ok(None);
+        }
+        Ok(Some(self.graph().order(&[instruction_label.to_string()])?))
+    }
+    pub fn graph(&self) -> DependencyGraph {
+        DependencyGraph::from_recipe(self)
     }
     pub fn with_path(&mut self, path: &str) -> Recipe {
         self.path = Some(path.to_string());
@@ -178,12 +327,27 @@ impl Recipe {
     pub fn instructions(&self) -> BTreeMap<String, Vec<Instruction>> {
         self.inst.clone()
     }
+    /// The labels of the instructions in the order they were declared.
+    pub fn labels(&self) -> Vec<String> {
+        self.order.clone()
+    }
+    /// The label set with `set default`, performed when no other is asked
+    /// for instead of the first one declared.
+    pub fn default_label(&self) -> Option<String> {
+        self.default.clone()
+    }
+    pub fn set_default_label(&mut self, label: &str) {
+        self.default = Some(label.to_string());
+    }
     pub fn main_instruction(&self) -> Result<Instruction, Error> {
+        if let Some(label) = &self.default {
+            return self.get_instructions(label).first().cloned().ok_or(Error::UnknownInstruction(label.clone()));
+        }
         match self.order.first() {
             None => Err(Error::UnstructedRecipe(format!("{:?} appears to be empty of instructions", self))),
             Some(key) => {
                 match self.inst.get(key) {
-                    Some(instructions) => if instructions.len() > 0 {
+                    Some(instructions) => if !instructions.is_empty() {
                         Ok(instructions[0].clone())
                     } else {
                         Err(Error::UnstructedRecipe(format!("{:?} appears to be empty of instructions", self)))
@@ -193,14 +357,14 @@ impl Recipe {
             }
         }
     }
+    /// The instructions labeled `name`, none when there is no such label.
     pub fn get_instructions(&self, name: &str) -> Vec<Instruction> {
-        match self.inst.get(name) {
-            Some(instructions) => instructions.clone(),
-            None => vec![self.main_instruction().unwrap()],
-        }
+        self.inst.get(name).cloned().unwrap_or_default()
     }
     pub fn add_instruction(&mut self, instruction: Instruction) {
-        self.order.insert(instruction.name());
+        if !self.order.contains(&instruction.name()) {
+            self.order.push(instruction.name());
+        }
         match self.inst.get_mut(&instruction.name()) {
             Some(instructions) => {
                 instructions.push(instruction);
@@ -215,6 +379,7 @@ impl Recipe {
         }
     }
     pub fn add_ingredient(&mut self, name: &str, substance: &str) {
+        self.baked.remove(name);
         match self.ings.get_mut(name) {
             Some(ingredient) => {
                 ingredient.push_str(substance)
@@ -222,31 +387,275 @@ impl Recipe {
             None => {
                 self.ings
                     .insert(
-                        format!("{}", name),
-                        format!("{}", substance),
+                        name.to_string(),
+                        Ingredient::Substance(substance.to_string()),
                     );
             }
         }
     }
-    pub fn translate_instruction(&mut self, instruction: &Instruction) -> Vec<String> {
+    pub fn set_ingredient(&mut self, name: &str, substance: &str) {
+        self.baked.remove(name);
+        self.ings.insert(name.to_string(), Ingredient::Substance(substance.to_string()));
+    }
+    /// Applies the `NAME=value` ingredient overrides among `args`, which win
+    /// over whatever the Bakefile declares, and returns the other arguments.
+    pub fn apply_overrides(&mut self, args: Vec<String>) -> Result<Vec<String>, Error> {
+        let (overrides, targets): (Vec<String>, Vec<String>) = args.into_iter().partition(|arg| arg.contains('='));
+        for arg in overrides {
+            let (name, value) = parse_override(&arg)?;
+            self.set_ingredient(&name, &value);
+        }
+        Ok(targets)
+    }
+    /// Keeps the values of the command ingredients that `other`, a clone of
+    /// this recipe, has evaluated since, unless they are known here already.
+    pub fn keep_baked(&mut self, other: &Recipe) {
+        for (name, value) in &other.baked {
+            self.baked.entry(name.clone()).or_insert_with(|| value.clone());
+        }
+    }
+    pub fn set_command_ingredient(&mut self, name: &str, command: &str) {
+        self.baked.remove(name);
+        self.ings.insert(name.to_string(), Ingredient::Command(command.to_string()));
+    }
+    /// The shell set with `set shell`, used by every instruction that
+    /// doesn't name its own.
+    pub fn shell(&self) -> Option<String> {
+        self.shell.clone()
+    }
+    pub fn set_shell(&mut self, shell: &str) {
+        self.shell = Some(shell.to_string());
+    }
+    /// Whether `set oneshell` makes every instruction run its steps by a
+    /// single shell.
+    pub fn oneshell(&self) -> bool {
+        self.oneshell
+    }
+    pub fn set_oneshell(&mut self, oneshell: bool) {
+        self.oneshell = oneshell;
+    }
+    pub fn ingredient(&self, name: &str) -> Option<String> {
+        self.ings.get(name).map(|ingredient| ingredient.text())
+    }
+    pub fn ingredients(&self) -> BTreeMap<String, Ingredient> {
+        self.ings.clone()
+    }
+    pub fn translate_instruction(&mut self, instruction: &Instruction) -> Result<Vec<String>, Error> {
+        self.translate_instruction_with(instruction, &Sh::new(None))
+    }
+    /// Like `translate_instruction` but evaluates command ingredients with
+    /// `shell`.
+    pub fn translate_instruction_with(&mut self, instruction: &Instruction, shell: &dyn Shell) -> Result<Vec<String>, Error> {
         let mut steps = Vec::<String>::new();
         for step in instruction.steps() {
-            let mut step = step.clone();
-            for (k, v) in self.ings.iter() {
-                let pat = format!("%[{}]", k);
-                step = step.replace(&pat, v);
+            steps.push(self.translate_step_with(instruction, &step, shell)?);
+        }
+        Ok(steps)
+    }
+    /// Like `translate_instruction` but leaves command ingredients unevaluated,
+    /// writing them as `$(command)` instead, so that nothing gets run.
+    pub fn preview_instruction(&mut self, instruction: &Instruction) -> Result<Vec<String>, Error> {
+        let name = instruction.name();
+        let mut steps = Vec::<String>::new();
+        for step in instruction.steps() {
+            let mut expansion = Expansion {
+                instruction: &name,
+                step: &step,
+                shell: None,
+                expanding: Vec::new(),
+            };
+            steps.push(self.expand(&step, &mut expansion)?);
+        }
+        Ok(steps)
+    }
+    pub fn translate_step(&mut self, instruction: &Instruction, step: &str) -> Result<String, Error> {
+        self.translate_step_with(instruction, step, &Sh::new(None))
+    }
+    /// Like `translate_step` but evaluates command ingredients with `shell`.
+    /// Their values are cached for as long as the recipe lives.
+    pub fn translate_step_with(&mut self, instruction: &Instruction, step: &str, shell: &dyn Shell) -> Result<String, Error> {
+        let name = instruction.name();
+        let mut expansion = Expansion {
+            instruction: &name,
+            step,
+            shell: Some(shell),
+            expanding: Vec::new(),
+        };
+        self.expand(step, &mut expansion)
+    }
+    /// Substitutes the placeholders of `text`, expanding ingredients that
+    /// reference other ingredients.
+    fn expand(&mut self, text: &str, expansion: &mut Expansion) -> Result<String, Error> {
+        substitute(text, |inner| {
+            let placeholder = Placeholder::parse(inner);
+            let value = match placeholder.source {
+                Source::Ingredient => match self.ings.get(placeholder.name).cloned() {
+                    Some(ingredient) => Some(self.expand_ingredient(placeholder.name, &ingredient, expansion)?),
+                    None => None,
+                },
+                Source::Environment => std::env::var(placeholder.name).ok(),
+            };
+            match (value, placeholder.fallback) {
+                (Some(value), Fallback::Nothing) => Ok(value),
+                (Some(value), _) if !value.is_empty() => Ok(value),
+                (_, Fallback::Default(default)) => self.expand(default, expansion),
+                (_, Fallback::Required(message)) => Err(Error::RequiredIngredient {
+                    name: placeholder.name.to_string(),
+                    message: message.to_string(),
+                    instruction: expansion.instruction.to_string(),
+                    step: expansion.step.to_string(),
+                }),
+                (None, Fallback::Nothing) => Err(Error::UndefinedIngredient {
+                    name: inner.to_string(),
+                    instruction: expansion.instruction.to_string(),
+                    step: expansion.step.to_string(),
+                }),
+            }
+        })
+    }
+    fn expand_ingredient(&mut self, name: &str, ingredient: &Ingredient, expansion: &mut Expansion) -> Result<String, Error> {
+        if let Some(value) = self.baked.get(name) {
+            return Ok(value.clone());
+        }
+        if let Some(position) = expansion.expanding.iter().position(|n| n == name) {
+            let mut cycle = expansion.expanding[position..].to_vec();
+            cycle.push(name.to_string());
+            return Err(Error::IngredientCycle(cycle));
+        }
+        expansion.expanding.push(name.to_string());
+        let expanded = self.expand(&ingredient.text(), expansion);
+        expansion.expanding.pop();
+        match ingredient {
+            Ingredient::Substance(_) => expanded,
+            Ingredient::Command(_) => {
+                let command = expanded?;
+                let Some(shell) = expansion.shell else {
+                    return Ok(format!("$({})", command));
+                };
+                let output = shell.capture(&command)?;
+                if !output.status.success() {
+                    return Err(Error::IngredientCommandFailed {
+                        name: name.to_string(),
+                        command,
+                        code: output.status.code(),
+                    });
+                }
+                let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                self.baked.insert(name.to_string(), value.clone());
+                Ok(value)
             }
-            steps.push(step);
         }
-        steps
     }
 }
 
+/// Splits an ingredient override given as `NAME=value`.
+pub fn parse_override(arg: &str) -> Result<(String, String), Error> {
+    match arg.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(Error::InvalidOverride(arg.to_string())),
+    }
+}
+
+/// State of the substitution of a single step. `expanding` holds the
+/// ingredients being expanded further up, so that a reference back to one
+/// of them is reported as a cycle instead of recursing forever. Without a
+/// `shell`, command ingredients are left as `$(command)`.
+struct Expansion<'a, 's> {
+    instruction: &'a str,
+    step: &'a str,
+    shell: Option<&'a dyn Shell<'s>>,
+    expanding: Vec<String>,
+}
+
+enum Source {
+    Ingredient,
+    Environment,
+}
+
+enum Fallback<'a> {
+    Nothing,
+    Default(&'a str),
+    Required(&'a str),
+}
+
+/// The parsed contents of a `%[...]` placeholder:
+///
+/// - `%[NAME]` the value of an ingredient
+/// - `%[env:NAME]` the value of an environment variable
+/// - `%[NAME:-default]` falls back to `default` when unset or empty
+/// - `%[NAME:?message]` fails with `message` when unset or empty
+struct Placeholder<'a> {
+    source: Source,
+    name: &'a str,
+    fallback: Fallback<'a>,
+}
+
+impl Placeholder<'_> {
+    fn parse(inner: &str) -> Placeholder<'_> {
+        let (source, rest) = match inner.strip_prefix("env:") {
+            Some(rest) => (Source::Environment, rest),
+            None => (Source::Ingredient, inner),
+        };
+        let (name, fallback) = match (rest.find(":-"), rest.find(":?")) {
+            (Some(d), Some(r)) if r < d => (&rest[..r], Fallback::Required(&rest[r + 2..])),
+            (Some(d), _) => (&rest[..d], Fallback::Default(&rest[d + 2..])),
+            (None, Some(r)) => (&rest[..r], Fallback::Required(&rest[r + 2..])),
+            (None, None) => (rest, Fallback::Nothing),
+        };
+        Placeholder { source, name, fallback }
+    }
+}
+
+/// Position of the `]` closing a placeholder whose `%[` has already been
+/// consumed, skipping over nested placeholders.
+fn closing_bracket(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut index = 0;
+    while index < text.len() {
+        if text[index..].starts_with("%[") {
+            depth += 1;
+            index += 2;
+            continue;
+        }
+        if text[index..].starts_with(']') {
+            if depth == 0 {
+                return Some(index);
+            }
+            depth -= 1;
+        }
+        index += text[index..].chars().next().map(|c| c.len_utf8()).unwrap_or(1);
+    }
+    None
+}
+
+/// Replaces every `%[...]` placeholder in `step` with whatever `lookup`
+/// returns for its contents. An unterminated `%[` is left as written.
+fn substitute<F>(step: &str, mut lookup: F) -> Result<String, Error>
+where
+    F: FnMut(&str) -> Result<String, Error>,
+{
+    let mut translated = String::new();
+    let mut rest = step;
+    while let Some(start) = rest.find("%[") {
+        let Some(end) = closing_bracket(&rest[start + 2..]) else {
+            break;
+        };
+        translated.push_str(&rest[..start]);
+        translated.push_str(&lookup(&rest[start + 2..start + 2 + end])?);
+        rest = &rest[start + 2 + end + 1..];
+    }
+    translated.push_str(rest);
+    Ok(translated)
+}
+
 
 #[cfg(test)]
 mod recipe_tests {
     use std::collections::BTreeMap;
+    use crate::graph::DependencyEdge;
     use crate::ing::{Recipe, Instruction};
+    use crate::lex::Span;
+    use crate::pars::parse_recipe;
     use crate::Error;
 
     #[test]
@@ -273,15 +682,104 @@ mod recipe_tests {
         let mut recipe = Recipe::blank();
         let inst1 = Instruction::with_action("show-ingredient", "echo %[ING1]");
         recipe.add_ingredient("ING1", "sauce");
-        assert_eq!(recipe.translate_instruction(&inst1), vec!["echo sauce".to_string()]);
+        assert_eq!(recipe.translate_instruction(&inst1)?, vec!["echo sauce".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_undefined_ingredient() {
+        let mut recipe = Recipe::blank();
+        let inst1 = Instruction::with_action("show-ingredient", "echo %[ING1] %[ING2]");
+        recipe.add_ingredient("ING1", "sauce");
+        assert_eq!(
+            recipe.translate_instruction(&inst1).unwrap_err().to_string(),
+            "UndefinedIngredient: %[ING2] is not defined (instruction \"show-ingredient\", step \"echo %[ING1] %[ING2]\")",
+        );
+    }
+
+    #[test]
+    fn test_ingredient_defaults() -> Result<(), Error>{
+        let mut recipe = Recipe::blank();
+        recipe.add_ingredient("ING1", "sauce");
+        recipe.add_ingredient("EMPTY", "");
+        let inst1 = Instruction::with_action("defaults", "echo %[ING1:-x] %[ING2:-y] %[EMPTY:-z] %[ING3:-%[ING1]-%[ING2:-w]]");
+        assert_eq!(recipe.translate_instruction(&inst1)?, vec!["echo sauce y z sauce-w".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_environment_ingredients() -> Result<(), Error>{
+        let mut recipe = Recipe::blank();
+        let inst1 = Instruction::with_action("env", "echo %[env:PATH] %[env:BAKEFILE_UNSET_VARIABLE:-unset]");
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(recipe.translate_instruction(&inst1)?, vec![format!("echo {} unset", path)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_ingredients() -> Result<(), Error>{
+        let mut recipe = Recipe::blank();
+        recipe.add_ingredient("OUT", "%[BUILD]/bin");
+        recipe.add_ingredient("BUILD", "%[ROOT]/build");
+        recipe.add_ingredient("ROOT", "/tmp");
+        let inst1 = Instruction::with_action("install", "cp bake %[OUT] && ls %[OUT]");
+        assert_eq!(recipe.translate_instruction(&inst1)?, vec!["cp bake /tmp/build/bin && ls /tmp/build/bin".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_overrides() -> Result<(), Error>{
+        let mut recipe = parse_recipe("PLAIN = file
+LIST = a
+LIST += b
+CMD != exit 1
+all:
+      echo %[PLAIN] %[LIST] %[CMD]
+")?;
+        let args = ["PLAIN=1", "all", "LIST=2", "CMD=3=4"].iter().map(|a| a.to_string()).collect();
+        assert_eq!(recipe.apply_overrides(args)?, vec!["all".to_string()]);
+        let inst1 = recipe.get_instructions("all")[0].clone();
+        assert_eq!(recipe.translate_instruction(&inst1)?, vec!["echo 1 2 3=4".to_string()]);
         Ok(())
     }
 
+    #[test]
+    fn test_invalid_override() {
+        let mut recipe = Recipe::blank();
+        assert_eq!(
+            recipe.apply_overrides(vec!["all".to_string(), "=x".to_string()]).unwrap_err().to_string(),
+            "InvalidOverride: expected NAME=value, got \"=x\"",
+        );
+        assert_eq!(recipe.ingredients().len(), 0);
+    }
+
+    #[test]
+    fn test_ingredient_cycles() {
+        let mut recipe = Recipe::blank();
+        recipe.add_ingredient("A", "%[B]");
+        recipe.add_ingredient("B", "x%[C:-%[A]]");
+        recipe.add_ingredient("SELF", "%[SELF]");
+        let inst1 = Instruction::with_action("cycle", "echo %[A]");
+        assert_eq!(recipe.translate_instruction(&inst1).unwrap_err().to_string(), "IngredientCycle: A -> B -> A");
+        let inst2 = Instruction::with_action("cycle", "echo %[SELF]");
+        assert_eq!(recipe.translate_instruction(&inst2).unwrap_err().to_string(), "IngredientCycle: SELF -> SELF");
+    }
+
+    #[test]
+    fn test_required_ingredient() {
+        let mut recipe = Recipe::blank();
+        let inst1 = Instruction::with_action("release", "git tag %[VERSION:?pass VERSION=x.y.z]");
+        assert_eq!(
+            recipe.translate_instruction(&inst1).unwrap_err().to_string(),
+            "RequiredIngredient: %[VERSION] pass VERSION=x.y.z (instruction \"release\", step \"git tag %[VERSION:?pass VERSION=x.y.z]\")",
+        );
+    }
+
     #[test]
     fn test_dependency_resolution_two_dimensional() -> Result<(), Error>{
         let mut recipe = Recipe::with_instruction(Instruction::with_action("bar", "echo 'and the bunnymen'"));
         recipe.add_instruction(Instruction::with_dependencies("foo", &[ "echo 'and the bunnymen'"], &["bar"]));
-        assert_eq!(recipe.resolve_dependencies("foo"), Some(vec!["bar".to_string(), "foo".to_string()]));
+        assert_eq!(recipe.resolve_dependencies("foo")?, Some(vec!["bar".to_string(), "foo".to_string()]));
         Ok(())
     }
 
@@ -290,7 +788,46 @@ mod recipe_tests {
         let mut recipe = Recipe::with_instruction(Instruction::with_action("gamel", "echo 'G'"));
         recipe.add_instruction(Instruction::with_dependencies("aleph", &[ "echo 'A'"], &["bet"]));
         recipe.add_instruction(Instruction::with_dependencies("bet", &[ "echo 'B'"], &["gamel"]));
-        assert_eq!(recipe.resolve_dependencies("aleph"), Some(vec!["gamel".to_string(), "bet".to_string(), "aleph".to_string()]));
+        assert_eq!(recipe.resolve_dependencies("aleph")?, Some(vec!["gamel".to_string(), "bet".to_string(), "aleph".to_string()]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dependency_resolution_unknown_label() -> Result<(), Error>{
+        let recipe = Recipe::with_instruction(Instruction::with_action("bar", "echo 'and the bunnymen'"));
+        assert_eq!(recipe.resolve_dependencies("echo")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dependency_resolution_two_dimensional_cycle() {
+        let mut recipe = Recipe::with_instruction(Instruction::with_dependencies("a", &[], &["b"]));
+        recipe.add_instruction(Instruction::with_dependencies("b", &[], &["a"]));
+        assert_eq!(recipe.resolve_dependencies("a").unwrap_err().to_string(), "DependencyCycle: a -> b -> a
+  a -> b
+  b -> a");
+    }
+
+    #[test]
+    fn test_dependency_resolution_self_cycle() {
+        let recipe = Recipe::with_instruction(Instruction::with_dependencies("a", &["echo a"], &["a"]));
+        assert_eq!(recipe.resolve_dependencies("a").unwrap_err().to_string(), "DependencyCycle: a -> a
+  a -> a");
+    }
+
+    #[test]
+    fn test_dependency_resolution_cycle_locations() -> Result<(), Error>{
+        let recipe = parse_recipe("all: aleph\naleph: bet\nbet: gamel\ngamel: aleph\n")?;
+        match recipe.resolve_dependencies("all") {
+            Err(Error::DependencyCycle(edges)) => {
+                assert_eq!(edges, vec![
+                    DependencyEdge { from: "aleph".to_string(), to: "bet".to_string(), span: Some(Span::new(18, 21, 2, 8)) },
+                    DependencyEdge { from: "bet".to_string(), to: "gamel".to_string(), span: Some(Span::new(27, 32, 3, 6)) },
+                    DependencyEdge { from: "gamel".to_string(), to: "aleph".to_string(), span: Some(Span::new(40, 45, 4, 8)) },
+                ]);
+            },
+            other => panic!("unexpected {:?}", other),
+        }
         Ok(())
     }
 }
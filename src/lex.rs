@@ -0,0 +1,430 @@
+use serde::{Serialize, Deserialize};
+use crate::diag::{Diagnostic, Diagnostics};
+use crate::errors::Error;
+
+pub const INDENTATION: usize = 6;
+
+/// Location of a piece of a Bakefile: byte offsets into the source plus the
+/// 1-based line and column of its first character.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, column: usize) -> Span {
+        Span { start, end, line, column }
+    }
+    pub fn to(&self, other: &Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end,
+            line: self.line,
+            column: self.column,
+        }
+    }
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Label(String),
+    Colon,
+    Dependency(String),
+    Indent(usize),
+    Step(String),
+    Comment(String),
+    Ingredient(String),
+    Equals,
+    PlusEquals,
+    BangEquals,
+    Value(String),
+    Setting(String),
+    Attribute(String),
+    Newline,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+struct Line<'a> {
+    text: &'a str,
+    offset: usize,
+    number: usize,
+}
+
+impl Line<'_> {
+    fn span(&self, start: usize, end: usize) -> Span {
+        Span::new(
+            self.offset + start,
+            self.offset + end,
+            self.number,
+            self.text[..start].chars().count() + 1,
+        )
+    }
+}
+
+pub fn tokenize(data: &str) -> Result<Vec<Token>, Error> {
+    let (tokens, diagnostics) = lex(data);
+    if diagnostics.is_empty() {
+        Ok(tokens)
+    } else {
+        Err(Error::from(diagnostics.with_source(data)))
+    }
+}
+
+/// Splits a Bakefile into tokens. A line that fails to lex is reported and
+/// dropped as a whole so that the following lines still get checked.
+pub fn lex(data: &str) -> (Vec<Token>, Diagnostics) {
+    let mut lexer = Lexer { tokens: Vec::new(), fresh: false, script: false };
+    let mut diagnostics = Diagnostics::new();
+    let mut offset = 0;
+    for (index, raw) in data.split_inclusive('\n').enumerate() {
+        let text = raw.strip_suffix('\n').unwrap_or(raw);
+        let text = text.strip_suffix('\r').unwrap_or(text);
+        let line = Line { text, offset, number: index + 1 };
+        let checkpoint = lexer.tokens.len();
+        if let Err(diagnostic) = lexer.lex_line(&line) {
+            lexer.tokens.truncate(checkpoint);
+            diagnostics.push(*diagnostic);
+        }
+        if raw.ends_with('\n') {
+            // The line ending may be `\r\n`, which `text` no longer holds.
+            lexer.push(TokenKind::Newline, line.span(text.len(), raw.len()));
+        }
+        offset += raw.len();
+    }
+    (lexer.tokens, diagnostics)
+}
+
+/// Finds the `#` that starts a trailing comment in a step, following shell
+/// rules: it must be unquoted, unescaped and preceded by whitespace.
+pub fn comment_start(step: &str) -> Option<usize> {
+    let mut single = false;
+    let mut double = false;
+    let mut escaped = false;
+    let mut previous = ' ';
+    for (index, c) in step.char_indices() {
+        if escaped {
+            escaped = false;
+            previous = '\\';
+            continue;
+        } else if single {
+            single = c != '\'';
+        } else {
+            match c {
+                '\\' => escaped = true,
+                '\'' if !double => single = true,
+                '"' => double = !double,
+                '#' if !double && previous.is_whitespace() => return Some(index),
+                _ => {}
+            }
+        }
+        previous = c;
+    }
+    None
+}
+
+struct Lexer {
+    tokens: Vec<Token>,
+    // a header was just read and no step yet
+    fresh: bool,
+    // inside the body of a `#!` instruction, which is kept as it is
+    script: bool,
+}
+
+impl Lexer {
+    fn push(&mut self, kind: TokenKind, span: Span) {
+        self.tokens.push(Token { kind, span });
+    }
+    fn lex_line(&mut self, line: &Line) -> Result<(), Box<Diagnostic>> {
+        let rest = line.text.trim_start_matches(' ');
+        let indent = line.text.len() - rest.len();
+        if rest.trim().is_empty() {
+            if self.script {
+                self.script_line(line);
+            }
+            return Ok(());
+        }
+        if rest.starts_with('\t') {
+            return Err(Box::new(Diagnostic::new("tabs are not supported", line.span(indent, indent + 1))
+                .with_hint(&format!("indent steps with {} spaces", INDENTATION))));
+        }
+        if indent == 0 {
+            self.fresh = false;
+            self.script = false;
+            if rest.starts_with('#') {
+                self.comment(line, 0);
+                return Ok(());
+            }
+            if rest.starts_with('[') {
+                return self.attribute(line);
+            }
+            return self.statement(line);
+        }
+        if (self.script && indent >= INDENTATION) || (self.fresh && indent == INDENTATION && rest.starts_with("#!")) {
+            self.script_line(line);
+            return Ok(());
+        }
+        self.push(TokenKind::Indent(indent), line.span(0, indent));
+        if rest.starts_with('#') {
+            self.comment(line, indent);
+            return Ok(());
+        }
+        if indent != INDENTATION {
+            return Err(Box::new(Diagnostic::new(&format!("got {} spaces instead of {}", indent, INDENTATION), line.span(indent, indent + 1))
+                .with_hint(&format!("steps must be indented with exactly {} spaces", INDENTATION))));
+        }
+        self.step(line, indent);
+        Ok(())
+    }
+    fn statement(&mut self, line: &Line) -> Result<(), Box<Diagnostic>> {
+        let text = line.text;
+        let end = text.find(|c: char| c == ':' || c == '#' || c == '=' || c.is_whitespace()).unwrap_or(text.len());
+        if end == 0 {
+            let c = text.chars().next().unwrap_or_default();
+            return Err(Box::new(Diagnostic::new(&format!("unexpected character {:?}", c), line.span(0, c.len_utf8()))
+                .with_hint("lines without indentation must start with an instruction label, an ingredient or `#`")));
+        }
+        let mut operator = end + (text[end..].len() - text[end..].trim_start().len());
+        let mut name_end = end;
+        if operator == end && text[end..].starts_with('=') && (text[..end].ends_with('+') || text[..end].ends_with('!')) {
+            name_end -= 1;
+            operator -= 1;
+        }
+        if &text[..end] == "set" && operator > end && text[operator..].starts_with(|c: char| c.is_alphabetic()) {
+            self.setting(line, operator);
+            return Ok(());
+        }
+        for (symbol, kind) in [("+=", TokenKind::PlusEquals), ("!=", TokenKind::BangEquals), ("=", TokenKind::Equals)] {
+            if text[operator..].starts_with(symbol) {
+                self.ingredient(line, name_end, operator, kind, symbol.len());
+                return Ok(());
+            }
+        }
+        if !text[end..].starts_with(':') {
+            return Err(Box::new(Diagnostic::new(&format!("expected `:` after label {:?}", &text[..end]), line.span(end, end + 1))
+                .with_hint("instruction headers look like `label: dependency ...` and ingredients like `NAME = value`")));
+        }
+        self.header(line, end);
+        Ok(())
+    }
+    fn ingredient(&mut self, line: &Line, end: usize, operator: usize, kind: TokenKind, width: usize) {
+        let text = line.text;
+        self.push(TokenKind::Ingredient(text[..end].to_string()), line.span(0, end));
+        self.push(kind, line.span(operator, operator + width));
+        self.value(line, operator + width, text.len());
+    }
+    /// `set NAME value`, where the value runs up to a trailing comment.
+    fn setting(&mut self, line: &Line, start: usize) {
+        let text = line.text;
+        let end = text[start..].find(char::is_whitespace).map(|e| start + e).unwrap_or(text.len());
+        self.push(TokenKind::Setting(text[start..end].to_string()), line.span(0, end));
+        self.value(line, end, text.len());
+    }
+    /// `[NAME value]` on a line of its own, above an instruction header.
+    fn attribute(&mut self, line: &Line) -> Result<(), Box<Diagnostic>> {
+        let text = line.text;
+        let Some(close) = text.find(']') else {
+            return Err(Box::new(Diagnostic::new("expected `]` to close the attribute", line.span(text.len(), text.len() + 1))
+                .with_hint("attributes look like `[shell bash -c]`")));
+        };
+        let rest = &text[close + 1..];
+        let after = close + 1 + (rest.len() - rest.trim_start().len());
+        if after < text.len() && !text[after..].starts_with('#') {
+            return Err(Box::new(Diagnostic::new("unexpected text after the attribute", line.span(after, text.len()))
+                .with_hint("put the instruction header on the next line")));
+        }
+        let inner = &text[1..close];
+        let start = 1 + (inner.len() - inner.trim_start().len());
+        let end = text[start..close].find(char::is_whitespace).map(|e| start + e).unwrap_or(close);
+        self.push(TokenKind::Attribute(text[start..end].to_string()), line.span(0, end));
+        self.value(line, end, close);
+        if after < text.len() {
+            self.comment(line, after);
+        }
+        Ok(())
+    }
+    /// The trimmed text between `start` and `end`, stopping at a comment.
+    fn value(&mut self, line: &Line, start: usize, end: usize) {
+        let text = &line.text[..end];
+        let rest = &text[start..];
+        let start = start + (rest.len() - rest.trim_start().len());
+        let hash = comment_start(&text[start..]).map(|h| start + h).unwrap_or(end);
+        let value = text[start..hash].trim_end();
+        self.push(TokenKind::Value(value.to_string()), line.span(start, start + value.len()));
+        if hash < end {
+            self.comment(line, hash);
+        }
+    }
+    fn header(&mut self, line: &Line, end: usize) {
+        self.fresh = true;
+        let text = line.text;
+        self.push(TokenKind::Label(text[..end].to_string()), line.span(0, end));
+        self.push(TokenKind::Colon, line.span(end, end + 1));
+        let mut pos = end + 1;
+        while pos < text.len() {
+            let rest = &text[pos..];
+            let skipped = rest.len() - rest.trim_start().len();
+            pos += skipped;
+            if pos >= text.len() {
+                break;
+            }
+            if text[pos..].starts_with('#') {
+                self.comment(line, pos);
+                break;
+            }
+            let word = text[pos..].find(|c: char| c == '#' || c.is_whitespace()).map(|w| pos + w).unwrap_or(text.len());
+            self.push(TokenKind::Dependency(text[pos..word].to_string()), line.span(pos, word));
+            pos = word;
+        }
+    }
+    /// A line of a `#!` body: everything after the indentation is part of
+    /// the script, deeper indentation, `#` and trailing blanks included. A
+    /// blank line is an empty one, however far it is indented.
+    fn script_line(&mut self, line: &Line) {
+        let text = line.text;
+        let spaces = text.len() - text.trim_start_matches(' ').len();
+        let indent = if spaces >= INDENTATION { INDENTATION } else { text.len() };
+        self.fresh = false;
+        self.script = true;
+        self.push(TokenKind::Indent(indent), line.span(0, indent));
+        self.push(TokenKind::Step(text[indent..].to_string()), line.span(indent, text.len()));
+    }
+    fn step(&mut self, line: &Line, indent: usize) {
+        self.fresh = false;
+        let text = line.text;
+        let hash = comment_start(&text[indent..]).map(|h| indent + h).unwrap_or(text.len());
+        let command = text[indent..hash].trim_end();
+        if !command.is_empty() {
+            self.push(TokenKind::Step(command.to_string()), line.span(indent, indent + command.len()));
+        }
+        if hash < text.len() {
+            self.comment(line, hash);
+        }
+    }
+    fn comment(&mut self, line: &Line, start: usize) {
+        let text = line.text[start + 1..].to_string();
+        self.push(TokenKind::Comment(text), line.span(start, line.text.len()));
+    }
+}
+
+
+#[cfg(test)]
+mod lexer_tests {
+    use crate::lex::{tokenize, Span, Token, TokenKind};
+    use k9::assert_equal;
+    use crate::errors::Error;
+
+    #[test]
+    fn test_header_tokens() -> Result<(), Error> {
+        let tokens = tokenize("foo: bar baz # note")?;
+
+        assert_equal!(tokens, vec![
+            Token { kind: TokenKind::Label("foo".to_string()), span: Span::new(0, 3, 1, 1) },
+            Token { kind: TokenKind::Colon, span: Span::new(3, 4, 1, 4) },
+            Token { kind: TokenKind::Dependency("bar".to_string()), span: Span::new(5, 8, 1, 6) },
+            Token { kind: TokenKind::Dependency("baz".to_string()), span: Span::new(9, 12, 1, 10) },
+            Token { kind: TokenKind::Comment(" note".to_string()), span: Span::new(13, 19, 1, 14) },
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_tokens() -> Result<(), Error> {
+        let tokens = tokenize("foo:\n      echo hallö\n")?;
+
+        assert_equal!(tokens[3..].to_vec(), vec![
+            Token { kind: TokenKind::Indent(6), span: Span::new(5, 11, 2, 1) },
+            Token { kind: TokenKind::Step("echo hallö".to_string()), span: Span::new(11, 22, 2, 7) },
+            Token { kind: TokenKind::Newline, span: Span::new(22, 23, 2, 17) },
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_crlf_line_endings() -> Result<(), Error> {
+        let tokens = tokenize("all:\r\n      echo crlf\r\n")?;
+
+        assert_equal!(tokens, vec![
+            Token { kind: TokenKind::Label("all".to_string()), span: Span::new(0, 3, 1, 1) },
+            Token { kind: TokenKind::Colon, span: Span::new(3, 4, 1, 4) },
+            Token { kind: TokenKind::Newline, span: Span::new(4, 6, 1, 5) },
+            Token { kind: TokenKind::Indent(6), span: Span::new(6, 12, 2, 1) },
+            Token { kind: TokenKind::Step("echo crlf".to_string()), span: Span::new(12, 21, 2, 7) },
+            Token { kind: TokenKind::Newline, span: Span::new(21, 23, 2, 16) },
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrong_indentation() {
+        let error = tokenize("foo:\n    bar\n").unwrap_err();
+
+        assert_equal!(error.to_string(), "error: got 4 spaces instead of 6
+ --> 2:5
+  |
+2 |     bar
+  |     ^
+  = hint: steps must be indented with exactly 6 spaces
+
+error: could not parse recipe due to 1 error");
+    }
+
+    #[test]
+    fn test_shebang_body_is_kept_as_is() -> Result<(), Error> {
+        let tokens = tokenize("py:\n      #!/usr/bin/env python3\n      # hello\n      if True:\n          print(1)  # one\nnext:\n      # note\n")?;
+        let steps = tokens.iter().filter_map(|token| match &token.kind {
+            TokenKind::Step(step) => Some(step.as_str()),
+            _ => None,
+        }).collect::<Vec<&str>>();
+
+        assert_equal!(steps, vec!["#!/usr/bin/env python3", "# hello", "if True:", "    print(1)  # one"]);
+        assert_equal!(tokens.last().map(|token| token.kind.clone()), Some(TokenKind::Newline));
+        assert_equal!(tokens[tokens.len() - 2].kind, TokenKind::Comment(" note".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_shebang_body_keeps_blank_lines() -> Result<(), Error> {
+        let tokens = tokenize("sh:\n      #!/bin/sh\n      cat <<EOF\n      a  \n\n        \n      b\n      EOF\n")?;
+        let steps = tokens.iter().filter_map(|token| match &token.kind {
+            TokenKind::Step(step) => Some(step.as_str()),
+            _ => None,
+        }).collect::<Vec<&str>>();
+
+        assert_equal!(steps, vec!["#!/bin/sh", "cat <<EOF", "a  ", "", "  ", "b", "EOF"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_setting_and_attribute_tokens() -> Result<(), Error> {
+        let tokens = tokenize("set shell bash -c # strict\n[shell zsh]\nset = 1\n")?;
+
+        assert_equal!(tokens, vec![
+            Token { kind: TokenKind::Setting("shell".to_string()), span: Span::new(0, 9, 1, 1) },
+            Token { kind: TokenKind::Value("bash -c".to_string()), span: Span::new(10, 17, 1, 11) },
+            Token { kind: TokenKind::Comment(" strict".to_string()), span: Span::new(18, 26, 1, 19) },
+            Token { kind: TokenKind::Newline, span: Span::new(26, 27, 1, 27) },
+            Token { kind: TokenKind::Attribute("shell".to_string()), span: Span::new(27, 33, 2, 1) },
+            Token { kind: TokenKind::Value("zsh".to_string()), span: Span::new(34, 37, 2, 8) },
+            Token { kind: TokenKind::Newline, span: Span::new(38, 39, 2, 12) },
+            Token { kind: TokenKind::Ingredient("set".to_string()), span: Span::new(39, 42, 3, 1) },
+            Token { kind: TokenKind::Equals, span: Span::new(43, 44, 3, 5) },
+            Token { kind: TokenKind::Value("1".to_string()), span: Span::new(45, 46, 3, 7) },
+            Token { kind: TokenKind::Newline, span: Span::new(46, 47, 3, 8) },
+        ]);
+        Ok(())
+    }
+}
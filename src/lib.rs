@@ -1,10 +1,20 @@
 pub mod errors;
 pub use errors::*;
+pub mod diag;
+pub use diag::*;
+pub mod lex;
+pub use lex::*;
+pub mod ast;
+pub use ast::*;
 pub mod pars;
 pub use pars::*;
 pub mod ing;
 pub use ing::*;
+pub mod graph;
+pub use graph::*;
 pub mod execute;
 pub use execute::*;
+pub mod report;
+pub use report::*;
 pub mod bak;
 pub use bak::*;
@@ -1,5 +1,6 @@
 use clap::Parser;
-use bakefile::{parse_recipe_from_path, Baker};
+use std::io::IsTerminal;
+use bakefile::{parse_override, parse_recipe_from_path, Baker, Error, Verbosity};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -14,19 +15,95 @@ pub struct Cli {
     #[arg(short, long, help = "toggle safe output")]
     pub safe: bool,
 
-    #[arg(help = "specify instructions to follow")]
+    #[arg(short, long, default_value_t = 1, help = "number of instructions to run at the same time")]
+    pub jobs: usize,
+
+    #[arg(short, long, help = "keep going after a failure with the instructions that don't depend on it")]
+    pub keep_going: bool,
+
+    #[arg(long, value_name = "PROGRAM", help = "shell that runs the steps, e.g. \"bash -euo pipefail -c\"")]
+    pub shell: Option<String>,
+
+    #[arg(short = 'n', long, help = "print what would run, in which order, shell and directory, without running it")]
+    pub dry_run: bool,
+
+    #[arg(short, long, conflicts_with = "quiet", help = "print each step, prefixed with its instruction, before running it")]
+    pub verbose: bool,
+
+    #[arg(short, long, help = "only print the output of steps that fail")]
+    pub quiet: bool,
+
+    #[arg(long = "ingredient", value_name = "NAME=value", value_parser = parse_override, help = "override an ingredient of the recipe (repeatable)")]
+    pub ingredients: Vec<(String, String)>,
+
+    #[arg(help = "specify instructions to follow and NAME=value ingredient overrides")]
     pub instructions: Vec<String>,
 }
 
 
 fn main() {
     let params = Cli::parse();
-    let recipe = match parse_recipe_from_path(&params.bakefile) {
+    let mut recipe = match parse_recipe_from_path(&params.bakefile) {
         Ok(recipe) => recipe,
+        Err(Error::RecipeParsingError(diagnostics)) => {
+            eprintln!("{}", diagnostics.render(std::io::stderr().is_terminal()));
+            std::process::exit(1);
+        }
         Err(e) => {
             eprintln!("{}", e);
             std::process::exit(1);
         }
     };
-    Baker::new(params.cwd, params.safe, params.instructions).perform(recipe);
+    let instructions = match recipe.apply_overrides(params.instructions) {
+        Ok(instructions) => instructions,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    for (name, value) in &params.ingredients {
+        recipe.set_ingredient(name, value);
+    }
+    let verbosity = if params.verbose {
+        Verbosity::Verbose
+    } else if params.quiet {
+        Verbosity::Quiet
+    } else {
+        Verbosity::Normal
+    };
+    let baker = Baker::new(params.cwd, params.safe, instructions)
+        .with_jobs(params.jobs)
+        .with_keep_going(params.keep_going)
+        .with_shell(params.shell)
+        .with_verbosity(verbosity);
+    if params.dry_run {
+        match baker.plan(recipe) {
+            Ok(plan) => print!("{}", plan),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let report = match baker.perform(recipe) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    for instruction in report.failed() {
+        if let Some(e) = &instruction.error {
+            eprintln!("{}", e);
+        }
+    }
+    if params.keep_going && !(params.quiet && report.success()) {
+        eprintln!("{}", report);
+    }
+    match report.error() {
+        Some(Error::StepFailed { code, .. }) => std::process::exit(code.unwrap_or(3)),
+        Some(_) => std::process::exit(1),
+        None => (),
+    }
 }
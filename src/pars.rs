@@ -1,153 +1,265 @@
 use std::fs;
-use crate::ing::{Instruction, Recipe};
+use crate::diag::{Diagnostic, Diagnostics};
+use crate::ast::{AssignmentKind, Attribute, Comment, Dependency, Document, Header, IngredientNode, InstructionNode, Item, Setting, StepNode};
+use crate::ing::{Instruction, Recipe, Step};
+use crate::lex::{lex, Span, Token, TokenKind};
 use crate::errors::Error;
 
-fn comment_start(c: char) -> bool {
-    match c  {
-        '#' => true,
-        _ => false
-    }
+pub fn parse_recipe_from_path(path: &str) -> Result<Recipe, Error> {
+    let unparsed_file = fs::read_to_string(path)?;
+    parse_recipe(&unparsed_file).map_err(|e| match e {
+        Error::RecipeParsingError(diagnostics) => Error::from(diagnostics.with_path(path)),
+        e => e,
+    })
 }
 
-fn new_line(c: char) -> bool {
-    return c == '\n'
-}
 
-pub fn parse_recipe_from_path(path: &str) -> Result<Recipe, Error> {
-    let unparsed_file = fs::read_to_string(path).expect(&format!("failed to read path {}", path));
-    parse_recipe(&unparsed_file)
+pub fn parse_recipe(data: &str) -> Result<Recipe, Error> {
+    Ok(build_recipe(&parse_document(data)?))
 }
 
+pub fn parse_document(data: &str) -> Result<Document, Error> {
+    let (tokens, mut diagnostics) = lex(data);
+    let document = parse_tokens(tokens, &mut diagnostics);
+    if diagnostics.is_empty() {
+        Ok(document)
+    } else {
+        diagnostics.sort();
+        Err(Error::from(diagnostics.with_source(data)))
+    }
+}
 
-pub fn parse_recipe(data: &str) -> Result<Recipe, Error> {
+pub fn build_recipe(document: &Document) -> Recipe {
     let mut recipe = Recipe::blank();
-    let mut instruction = Instruction::new("");
-    let mut target_name = String::new();
-    let mut current_dependency = String::new();
-    let mut shell_command = String::new();
-    let mut inshell = false;
-    let mut dependency = false;
-    let mut indent = 0;
-    let mut lineno = 1;
-    let mut lpos = 1;
-    let mut pos = 0;
-    let mut incomment = false;
-    let indentation = 6;
-    let ac = data.chars();
-    for c in ac {
-        pos += 1;
-        if comment_start(c) {
-            incomment = true;
-            continue;
-        } else if new_line(c) {
-            if dependency {
-                if !current_dependency.is_empty() {
-                    instruction.add_dependency(&current_dependency);
-                    current_dependency.clear();
+    for item in &document.items {
+        match item {
+            Item::Instruction(node) => {
+                let mut instruction = Instruction::new(node.label());
+                for step in &node.steps {
+                    instruction.add_step(Step {
+                        command: step.command.clone(),
+                        silent: step.silent,
+                        ignore_errors: step.ignore_errors,
+                    });
                 }
-            } else if inshell {
-                if !shell_command.is_empty() {
-                    instruction.add_action(&shell_command.trim());
-                    shell_command.clear();
+                for dependency in &node.header.dependencies {
+                    instruction.add_dependency_at(&dependency.name, dependency.span);
                 }
-            }
-            lineno += 1;
-            lpos = 0;
-            indent = 0;
-            incomment = false;
-            inshell = false;
-            dependency = false;
-            continue;
+                for attribute in &node.attributes {
+                    match attribute.name.as_str() {
+                        "shell" => instruction.set_shell(&attribute.value),
+                        "oneshell" => instruction.set_oneshell(true),
+                        _ => {},
+                    }
+                }
+                recipe.add_instruction(instruction);
+            },
+            Item::Ingredient(node) => match node.kind {
+                AssignmentKind::Set => recipe.set_ingredient(&node.name, &node.value),
+                AssignmentKind::Append => match recipe.ingredient(&node.name) {
+                    Some(_) => recipe.add_ingredient(&node.name, &format!(" {}", node.value)),
+                    None => recipe.add_ingredient(&node.name, &node.value),
+                },
+                AssignmentKind::Command => recipe.set_command_ingredient(&node.name, &node.value),
+            },
+            Item::Setting(setting) => match setting.name.as_str() {
+                "shell" => recipe.set_shell(&setting.value),
+                "oneshell" => recipe.set_oneshell(true),
+                "default" => recipe.set_default_label(&setting.value),
+                _ => {},
+            },
+            Item::Comment(_) => {},
         }
-        if incomment {
-            continue;
+    }
+    recipe
+}
+
+/// Settings of a whole recipe, written `set NAME value`.
+const SETTINGS: &[&str] = &["shell", "oneshell", "default"];
+/// Settings of a single instruction, written `[NAME value]` above its header.
+const ATTRIBUTES: &[&str] = &["shell", "oneshell"];
+
+fn check_option(kind: &str, known: &[&str], name: &str, value: &str, span: Span) -> Option<Diagnostic> {
+    if !known.contains(&name) {
+        return Some(Diagnostic::new(&format!("unknown {} `{}`", kind, name), span)
+            .with_hint(&format!("known {}s are: {}", kind, known.join(", "))));
+    }
+    if name == "shell" && value.is_empty() {
+        return Some(Diagnostic::new("`shell` needs a program", span)
+            .with_hint("name a shell with its arguments, like `bash -euo pipefail -c`"));
+    }
+    if name == "default" && (value.is_empty() || value.contains(char::is_whitespace)) {
+        return Some(Diagnostic::new("`default` needs a single label", span)
+            .with_hint("name the instruction to perform when none is asked for, like `set default build`"));
+    }
+    if name == "oneshell" && !value.is_empty() {
+        return Some(Diagnostic::new("`oneshell` takes no value", span)
+            .with_hint("write it on its own to turn it on"));
+    }
+    None
+}
+
+fn check_items(items: &[Item], diagnostics: &mut Diagnostics) {
+    let labels = items.iter().filter_map(|item| match item {
+        Item::Instruction(instruction) => Some(instruction.label()),
+        _ => None,
+    }).collect::<Vec<&str>>();
+    for item in items {
+        let problems = match item {
+            Item::Setting(setting) if setting.name == "default" && !setting.value.is_empty() && !labels.contains(&setting.value.as_str()) => {
+                vec![Some(Diagnostic::new(&format!("there is no instruction labeled {:?} to make the default", setting.value), setting.span)
+                    .with_hint(&format!("declared instructions are: {}", labels.join(", "))))]
+            },
+            Item::Setting(setting) => vec![check_option("setting", SETTINGS, &setting.name, &setting.value, setting.span)],
+            Item::Instruction(instruction) => instruction.attributes.iter().map(|attribute| {
+                check_option("attribute", ATTRIBUTES, &attribute.name, &attribute.value, attribute.span)
+            }).collect(),
+            _ => Vec::new(),
+        };
+        for diagnostic in problems.into_iter().flatten() {
+            diagnostics.push(diagnostic);
         }
-        lpos += 1;
+    }
+}
+
+fn dangling(attribute: Attribute) -> Diagnostic {
+    Diagnostic::new("attribute is not followed by an instruction", attribute.span)
+        .with_hint("attributes go on the lines right above an instruction header")
+}
 
-        match c {
-            '\t' => {
-                continue
+fn current_instruction(items: &mut [Item], current: Option<usize>) -> Option<&mut InstructionNode> {
+    match current.and_then(|index| items.get_mut(index)) {
+        Some(Item::Instruction(instruction)) => Some(instruction),
+        _ => None,
+    }
+}
+
+fn parse_tokens(tokens: Vec<Token>, diagnostics: &mut Diagnostics) -> Document {
+    let mut items = Vec::<Item>::new();
+    let mut current: Option<usize> = None;
+    let mut indented = false;
+    let mut attributes = Vec::<Attribute>::new();
+    for token in tokens {
+        if !attributes.is_empty() && !matches!(token.kind, TokenKind::Label(_) | TokenKind::Attribute(_) | TokenKind::Value(_) | TokenKind::Comment(_) | TokenKind::Newline) {
+            for attribute in attributes.drain(..) {
+                diagnostics.push(dangling(attribute));
+            }
+        }
+        match token.kind {
+            TokenKind::Label(label) => {
+                let mut instruction = InstructionNode::new(Header {
+                    label,
+                    dependencies: Vec::new(),
+                    span: token.span,
+                });
+                instruction.attributes = std::mem::take(&mut attributes);
+                items.push(Item::Instruction(instruction));
+                current = Some(items.len() - 1);
             },
-            ':' => {
-                match indent {
-                    0 => {
-                        instruction.set_label(&target_name);
-                        target_name.clear();
-                        dependency = true;
-                    },
-                    i => if i == indentation {
-                        shell_command.push(c)
-                    } else {
-                        return Err(Error::RecipeParsingError(format!("invalid character {:?} at {}:{}:{}", c, lineno, lpos, pos)))
-                    },
-                }
-                continue;
+            TokenKind::Attribute(name) => {
+                attributes.push(Attribute { name, value: String::new(), span: token.span });
+                current = None;
             },
-            ' ' => {
-                if dependency {
-                    if current_dependency.len() > 0 {
-                        instruction.add_dependency(&current_dependency);
-                        current_dependency.clear();
-                    }
-                    continue
+            TokenKind::Setting(name) => {
+                items.push(Item::Setting(Setting { name, value: String::new(), span: token.span }));
+                current = None;
+            },
+            TokenKind::Colon => {
+                if let Some(instruction) = current_instruction(&mut items, current) {
+                    instruction.header.span = instruction.header.span.to(&token.span);
+                    instruction.extend_span(&token.span);
                 }
-                if !inshell && !incomment {
-                    indent += 1;
-                } else if !incomment {
-                    shell_command.push(c);
+            },
+            TokenKind::Dependency(name) => {
+                if let Some(instruction) = current_instruction(&mut items, current) {
+                    instruction.header.span = instruction.header.span.to(&token.span);
+                    instruction.header.dependencies.push(Dependency { name, span: token.span });
+                    instruction.extend_span(&token.span);
                 }
-                continue;
             },
-            '\r' => {
-                lpos = 0;
+            TokenKind::Indent(_) => {
+                indented = true;
             },
-            _ => {
-                if dependency {
-                    current_dependency.push(c);
-                    continue;
-                } else if inshell {
-                    shell_command.push(c);
-                    continue;
+            TokenKind::Step(command) => {
+                match current_instruction(&mut items, current) {
+                    Some(instruction) => {
+                        let script = instruction.steps.first().map(|step| &step.command).unwrap_or(&command).starts_with("#!");
+                        let step = if script { Step::new(&command) } else { Step::parse(&command) };
+                        instruction.steps.push(StepNode {
+                            command: step.command,
+                            silent: step.silent,
+                            ignore_errors: step.ignore_errors,
+                            span: token.span,
+                        });
+                        if !command.trim().is_empty() {
+                            instruction.extend_span(&token.span);
+                        }
+                    },
+                    None => diagnostics.push(Diagnostic::new("step outside of an instruction", token.span)
+                        .with_hint("declare an instruction like `label:` above its steps")),
                 }
-                match indent {
-                    0 => {
-                        target_name.push(c);
+            },
+            TokenKind::Comment(text) => {
+                let comment = Comment { text, span: token.span };
+                match current_instruction(&mut items, current) {
+                    Some(instruction) if indented || instruction.header.span.line == comment.span.line => {
+                        instruction.extend_span(&comment.span);
+                        instruction.comments.push(comment);
                     },
-                    f => {
-                        if comment_start(c) {
-                            incomment = true;
-                        } else if new_line(c) {
-                            incomment = false;
-                        } else if f == indentation {
-                            inshell = true;
-                            shell_command.push(c);
-                        } else {
-                            if indent != indentation {
-                                return Err(Error::RecipeParsingError(format!("got {} spaces instead of 6 at {}:{}:{}", indent, lineno, lpos, pos)))
-                            } else {
-                                return Err(Error::RecipeParsingError(format!("unhandled symbol: {:?} at line {}:{}:{}", c, lineno, lpos, pos)))
-                            }
-                        }
+                    _ => items.push(Item::Comment(comment)),
+                }
+            },
+            TokenKind::Ingredient(name) => {
+                items.push(Item::Ingredient(IngredientNode {
+                    name,
+                    kind: AssignmentKind::Set,
+                    value: String::new(),
+                    span: token.span,
+                }));
+                current = None;
+            },
+            TokenKind::Value(value) if !attributes.is_empty() => {
+                if let Some(attribute) = attributes.last_mut() {
+                    attribute.span = attribute.span.to(&token.span);
+                    attribute.value = value;
+                }
+            },
+            TokenKind::Value(value) if matches!(items.last(), Some(Item::Setting(_))) => {
+                if let Some(Item::Setting(setting)) = items.last_mut() {
+                    setting.span = setting.span.to(&token.span);
+                    setting.value = value;
+                }
+            },
+            TokenKind::Equals | TokenKind::PlusEquals | TokenKind::BangEquals | TokenKind::Value(_) => {
+                if let Some(Item::Ingredient(ingredient)) = items.last_mut() {
+                    ingredient.span = ingredient.span.to(&token.span);
+                    match token.kind {
+                        TokenKind::PlusEquals => ingredient.kind = AssignmentKind::Append,
+                        TokenKind::BangEquals => ingredient.kind = AssignmentKind::Command,
+                        TokenKind::Value(value) => ingredient.value = value,
+                        _ => {},
                     }
                 }
-            }
+            },
+            TokenKind::Newline => {
+                indented = false;
+            },
         }
     }
-    if !incomment {
-    if dependency {
-        if !current_dependency.is_empty() {
-            instruction.add_dependency(&current_dependency);
-            current_dependency.clear();
-        }
-    } else if inshell {
-        if !shell_command.is_empty() {
-            instruction.add_action(&shell_command.trim());
-            shell_command.clear();
-        }
+    for attribute in attributes {
+        diagnostics.push(dangling(attribute));
     }
+    // Blank lines only make it into `#!` bodies, where those between the
+    // body and whatever follows it are not part of the script.
+    for item in items.iter_mut() {
+        if let Item::Instruction(instruction) = item {
+            while instruction.steps.last().is_some_and(|step| step.command.trim().is_empty()) {
+                instruction.steps.pop();
+            }
+        }
     }
-    recipe.add_instruction(instruction);
-    Ok(recipe)
+    check_items(&items, diagnostics);
+    Document { items }
 }
 
 
@@ -163,7 +275,7 @@ mod unit_tests {
     #[test]
     fn test_target_name() -> Result<(), Error> {
         let input = "foo:";
-        let recipe = parse_recipe(&input)?;
+        let recipe = parse_recipe(input)?;
 
         assert_equal!(recipe, Recipe::with_instruction(Instruction::with_dependencies("foo", &[], &[])));
         Ok(())
@@ -173,7 +285,7 @@ mod unit_tests {
     fn test_target_and_command() -> Result<(), Error>  {
         let input = "foo:
       bar";
-        let recipe = parse_recipe(&input)?;
+        let recipe = parse_recipe(input)?;
 
         assert_equal!(recipe, Recipe::with_instruction(Instruction::with_dependencies("foo", &["bar"], &[])));
 
@@ -185,7 +297,7 @@ mod unit_tests {
       bar
       baz
 ";
-        let recipe = parse_recipe(&input)?;
+        let recipe = parse_recipe(input)?;
 
         assert_equal!(recipe, Recipe::with_instruction(Instruction::with_dependencies("foo", &["bar", "baz"], &[])));
         Ok(())
@@ -194,13 +306,101 @@ mod unit_tests {
     #[test]
     fn test_target_and_2_dependencies()  -> Result<(), Error> {
         let input = "foo: bar baz";
-        let recipe = parse_recipe(&input)?;
+        let recipe = parse_recipe(input)?;
 
         assert_equal!(recipe, Recipe::with_instruction(Instruction::with_dependencies("foo", &[], &["bar", "baz"])));
         Ok(())
     }
 
 
+}
+#[cfg(test)]
+mod document_tests {
+    use crate::pars::parse_document;
+    use k9::assert_equal;
+use crate::ast::{Comment, Dependency, Item};
+    use crate::lex::Span;
+    use crate::errors::{Error};
+
+    #[test]
+    fn test_spans() -> Result<(), Error> {
+        let input = "# cake
+foo: bar
+      baz # qux
+";
+        let document = parse_document(input)?;
+
+        assert_equal!(document.items.len(), 2);
+        assert_equal!(document.items[0], Item::Comment(Comment { text: " cake".to_string(), span: Span::new(0, 6, 1, 1) }));
+        let instruction = document.instructions().next().unwrap();
+        assert_equal!(instruction.header.span, Span::new(7, 15, 2, 1));
+        assert_equal!(instruction.header.dependencies, vec![Dependency { name: "bar".to_string(), span: Span::new(12, 15, 2, 6) }]);
+        assert_equal!(instruction.steps[0].command, "baz");
+        assert_equal!(instruction.steps[0].span, Span::new(22, 25, 3, 7));
+        assert_equal!(instruction.comments, vec![Comment { text: " qux".to_string(), span: Span::new(26, 31, 3, 11) }]);
+        assert_equal!(instruction.span, Span::new(7, 31, 2, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_prefixes() -> Result<(), Error> {
+        let document = parse_document("clean:\n      @echo cleaning\n      -rm -r build\n      @- rm log\n")?;
+        let steps = &document.instructions().next().unwrap().steps;
+
+        assert_equal!(steps.iter().map(|s| (s.command.as_str(), s.silent, s.ignore_errors)).collect::<Vec<(&str, bool, bool)>>(), vec![
+            ("echo cleaning", true, false),
+            ("rm -r build", false, true),
+            ("rm log", true, true),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shebang_body_ends_before_blank_lines() -> Result<(), Error> {
+        let document = parse_document("py:\n      #!/usr/bin/env python3\n      print(1)\n\n      print(2)\n\n\nnext:\n")?;
+        let instruction = document.instructions().next().unwrap();
+
+        assert_equal!(instruction.steps.iter().map(|s| s.command.as_str()).collect::<Vec<&str>>(), vec!["#!/usr/bin/env python3", "print(1)", "", "print(2)"]);
+        assert_equal!(instruction.span, Span::new(0, 63, 1, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_outside_of_instruction() {
+        match parse_document("      bar\n") {
+            Err(Error::RecipeParsingError(diagnostics)) => {
+                let diagnostic = diagnostics.iter().next().unwrap();
+                assert_equal!(diagnostic.message, "step outside of an instruction");
+                assert_equal!(diagnostic.line(), 1);
+                assert_equal!(diagnostic.column(), 7);
+                assert_equal!(diagnostic.source_line, "      bar");
+                assert_equal!(diagnostic.hint, Some("declare an instruction like `label:` above its steps".to_string()));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_all_errors_are_reported() {
+        let input = "foo:
+    bar
+      baz
+  qux
+      step
+bar baz
+";
+        match parse_document(input) {
+            Err(Error::RecipeParsingError(diagnostics)) => {
+                assert_equal!(diagnostics.iter().map(|d| (d.line(), d.message.clone())).collect::<Vec<(usize, String)>>(), vec![
+                    (2, "got 4 spaces instead of 6".to_string()),
+                    (4, "got 2 spaces instead of 6".to_string()),
+                    (6, "expected `:` after label \"bar\"".to_string()),
+                ]);
+                assert_equal!(diagnostics.summary(), "could not parse recipe due to 3 errors");
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
 }
 #[cfg(test)]
 mod comment_tests {
@@ -216,11 +416,212 @@ foo:
       bar
       # comment
 ";
-        let recipe = parse_recipe(&input)?;
+        let recipe = parse_recipe(input)?;
 
         assert_equal!(recipe, Recipe::with_instruction(Instruction::with_dependencies("foo", &["bar"], &[])));
         Ok(())
     }
+
+    #[test]
+    fn test_trailing_comment_after_step() -> Result<(), Error>  {
+        let recipe = parse_recipe("foo:\n      bar --baz # comment\n")?;
+
+        assert_equal!(recipe, Recipe::with_instruction(Instruction::with_dependencies("foo", &["bar --baz"], &[])));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_inside_quotes_is_not_a_comment() -> Result<(), Error>  {
+        let input = r#"foo:
+      echo "issue #42"
+      echo 'single #quoted' # comment
+      echo "escaped \" #quote"
+"#;
+        let recipe = parse_recipe(input)?;
+
+        assert_equal!(recipe, Recipe::with_instruction(Instruction::with_dependencies("foo", &[
+            r#"echo "issue #42""#,
+            "echo 'single #quoted'",
+            r#"echo "escaped \" #quote""#,
+        ], &[])));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_inside_a_word_is_not_a_comment() -> Result<(), Error>  {
+        let input = r#"foo:
+      curl http://host/#anchor
+      echo \#not-a-comment
+      echo escaped\ #space
+"#;
+        let recipe = parse_recipe(input)?;
+
+        assert_equal!(recipe, Recipe::with_instruction(Instruction::with_dependencies("foo", &[
+            "curl http://host/#anchor",
+            r#"echo \#not-a-comment"#,
+            r#"echo escaped\ #space"#,
+        ], &[])));
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod ingredient_tests {
+    use crate::pars::{parse_document, parse_recipe};
+    use k9::assert_equal;
+    use crate::ast::{AssignmentKind, IngredientNode};
+    use crate::ing::{Ingredient, Instruction};
+    use crate::lex::Span;
+    use crate::errors::{Error};
+
+    #[test]
+    fn test_ingredient_declaration() -> Result<(), Error> {
+        let document = parse_document("NAME = sauce # comment\n")?;
+
+        assert_equal!(document.ingredients().collect::<Vec<&IngredientNode>>(), vec![&IngredientNode {
+            name: "NAME".to_string(),
+            kind: AssignmentKind::Set,
+            value: "sauce".to_string(),
+            span: Span::new(0, 12, 1, 1),
+        }]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ingredient_substitution() -> Result<(), Error> {
+        let input = "FLOUR = rye
+FLOUR = wheat
+TOPPINGS=seeds
+TOPPINGS += salt
+EMPTY =
+
+bake:
+      echo %[FLOUR] with %[TOPPINGS]%[EMPTY]
+";
+        let mut recipe = parse_recipe(input)?;
+
+        assert_equal!(recipe.ingredient("FLOUR"), Some("wheat".to_string()));
+        assert_equal!(recipe.ingredient("EMPTY"), Some(String::new()));
+        let bake = recipe.get_instructions("bake")[0].clone();
+        assert_equal!(recipe.translate_instruction(&bake)?, vec!["echo wheat with seeds salt".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_ingredients() -> Result<(), Error> {
+        let input = "TODAY != echo '  2024-01-01  '
+STAMP!=echo %[TODAY]-%[SUFFIX]
+SUFFIX = x
+";
+        let mut recipe = parse_recipe(input)?;
+
+        assert_equal!(recipe.ingredients().get("TODAY"), Some(&Ingredient::Command("echo '  2024-01-01  '".to_string())));
+        assert_equal!(recipe.translate_instruction(&Instruction::with_action("x", "tar -czf %[STAMP].tgz"))?, vec!["tar -czf 2024-01-01-x.tgz".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_ingredients_are_lazy_and_cached() -> Result<(), Error> {
+        let marker = std::env::temp_dir().join(format!("bakefile-lazy-{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+        let input = format!("COUNT != echo x >> {} && wc -l < {}\nNEVER != false\n", marker.display(), marker.display());
+        let mut recipe = parse_recipe(&input)?;
+        let count = Instruction::with_action("count", "echo %[COUNT] %[COUNT]");
+
+        assert_equal!(marker.exists(), false);
+        assert_equal!(recipe.translate_instruction(&count)?, vec!["echo 1 1".to_string()]);
+        assert_equal!(recipe.translate_instruction(&count)?, vec!["echo 1 1".to_string()]);
+        let _ = std::fs::remove_file(&marker);
+        assert_equal!(
+            recipe.translate_instruction(&Instruction::with_action("never", "echo %[NEVER]")).unwrap_err().to_string(),
+            "IngredientCommandFailed: %[NEVER] command \"false\" exited with status 1"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_without_space() -> Result<(), Error> {
+        let mut recipe = parse_recipe("FLAGS+=-v\nFLAGS+= -q\n")?;
+
+        assert_equal!(recipe.translate_instruction(&Instruction::with_action("x", "cc %[FLAGS]"))?, vec!["cc -v -q".to_string()]);
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod setting_tests {
+    use crate::pars::{parse_document, parse_recipe};
+    use k9::assert_equal;
+    use crate::errors::{Error};
+
+    #[test]
+    fn test_shell_setting_and_attribute() -> Result<(), Error> {
+        let recipe = parse_recipe("set shell bash -euo pipefail -c
+build:
+      make
+[shell zsh]
+# wordy
+check: build
+      print -l *
+")?;
+
+        assert_equal!(recipe.shell(), Some("bash -euo pipefail -c".to_string()));
+        assert_equal!(recipe.get_instructions("build")[0].shell(), None);
+        assert_equal!(recipe.get_instructions("check")[0].shell(), Some("zsh".to_string()));
+        assert_equal!(recipe.get_instructions("check")[0].dependencies(), vec!["build".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_oneshell_setting_and_attribute() -> Result<(), Error> {
+        let recipe = parse_recipe("[oneshell]\n[shell bash]\ndeploy:\n      cd build\n")?;
+
+        assert_equal!(recipe.oneshell(), false);
+        assert_equal!(recipe.get_instructions("deploy")[0].oneshell(), true);
+        assert_equal!(recipe.get_instructions("deploy")[0].shell(), Some("bash".to_string()));
+        assert_equal!(parse_recipe("set oneshell # everywhere\n")?.oneshell(), true);
+        Ok(())
+    }
+
+    #[test]
+    fn test_declaration_order_and_default() -> Result<(), Error> {
+        let recipe = parse_recipe("build:\nall: build test\ntest:\n")?;
+        let chosen = parse_recipe("build:\nall: build test\ntest:\nset default all\n")?;
+
+        assert_equal!(recipe.labels(), vec!["build".to_string(), "all".to_string(), "test".to_string()]);
+        assert_equal!(recipe.main_instruction()?.name(), "build");
+        assert_equal!(recipe.to_string(), "Recipe[build, all, test]");
+        assert_equal!(chosen.default_label(), Some("all".to_string()));
+        assert_equal!(chosen.main_instruction()?.name(), "all");
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_and_dangling_options() {
+        match parse_document("set colour blue\nset shell\n[shell]\n[retries 3]\n[oneshell yes]\nbuild:\n[shell bash]\n") {
+            Err(Error::RecipeParsingError(diagnostics)) => {
+                assert_equal!(diagnostics.iter().map(|d| (d.line(), d.message.clone())).collect::<Vec<(usize, String)>>(), vec![
+                    (1, "unknown setting `colour`".to_string()),
+                    (2, "`shell` needs a program".to_string()),
+                    (3, "`shell` needs a program".to_string()),
+                    (4, "unknown attribute `retries`".to_string()),
+                    (5, "`oneshell` takes no value".to_string()),
+                    (7, "attribute is not followed by an instruction".to_string()),
+                ]);
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse_document("set default\nset default tset\ntest:\n[default]\nbuild:\n") {
+            Err(Error::RecipeParsingError(diagnostics)) => {
+                assert_equal!(diagnostics.iter().map(|d| (d.line(), d.message.clone())).collect::<Vec<(usize, String)>>(), vec![
+                    (1, "`default` needs a single label".to_string()),
+                    (2, "there is no instruction labeled \"tset\" to make the default".to_string()),
+                    (4, "unknown attribute `default`".to_string()),
+                ]);
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
 }
 
 
@@ -254,40 +655,40 @@ mod functional_tests {
         ]));
         Ok(())
     }
-    // #[test]
-    // fn test_parse_test_bakefile_0c3t3s()  -> Result<(), Error> {
-    //     let recipe = parse_recipe_from_path("tests/simple/Bakefile.0c3t3s")?;
-
-    //     assert_equal!(recipe, Recipe::with_instructions(vec![
-
-    //         Instruction::with_dependencies("hw", &[
-    //         ], &["en", "de"]),
-
-    //         Instruction::with_dependencies("en", &[
-    //             "echo \"hello world\"",
-    //         ], &[]),
-
-    //         Instruction::with_dependencies("de", &[
-    //             "echo \"hallö welt\" > /dev/random",
-    //         ], &[]),
-
-    //     ]));
-    //     Ok(())
-    // }
-    // #[test]
-    // fn test_parse_test_bakefile_3c3t3s()  -> Result<(), Error> {
-    //     let recipe = parse_recipe_from_path("tests/simple/Bakefile.3c3t3s")?;
-
-    //     assert_equal!(recipe, Recipe::with_instructions(vec![
-    //         Instruction::with_dependencies("hw", &[
-    //         ], &["en", "de"]),
-    //         Instruction::with_dependencies("en", &[
-    //             "echo \"hello world\"",
-    //         ], &[]),
-    //         Instruction::with_dependencies("de", &[
-    //             "echo \"hallö welt\" > /dev/random",
-    //         ], &[]),
-    //     ]));
-    //     Ok(())
-    // }
+    #[test]
+    fn test_parse_test_bakefile_0c3t3s()  -> Result<(), Error> {
+        let recipe = parse_recipe_from_path("tests/simple/Bakefile.0c3t3s")?;
+
+        assert_equal!(recipe, Recipe::with_instructions(vec![
+
+            Instruction::with_dependencies("hw", &[
+            ], &["en", "de"]),
+
+            Instruction::with_dependencies("en", &[
+                "echo \"hello world\"",
+            ], &[]),
+
+            Instruction::with_dependencies("de", &[
+                "echo \"hallö welt\" > /dev/random",
+            ], &[]),
+
+        ]));
+        Ok(())
+    }
+    #[test]
+    fn test_parse_test_bakefile_3c3t3s()  -> Result<(), Error> {
+        let recipe = parse_recipe_from_path("tests/simple/Bakefile.3c3t3s")?;
+
+        assert_equal!(recipe, Recipe::with_instructions(vec![
+            Instruction::with_dependencies("hw", &[
+            ], &["en", "de"]),
+            Instruction::with_dependencies("en", &[
+                "echo \"hello world\"",
+            ], &[]),
+            Instruction::with_dependencies("de", &[
+                "echo \"hallö welt\"",
+            ], &[]),
+        ]));
+        Ok(())
+    }
 }
@@ -0,0 +1,141 @@
+use crate::errors::Error;
+use crate::ing::Step;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+/// How a single step of an instruction went.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepReport {
+    pub command: String,
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+    pub duration: Duration,
+    pub silent: bool,
+    pub ignore_errors: bool,
+}
+
+impl StepReport {
+    pub fn new(step: &Step, status: &ExitStatus, duration: Duration) -> StepReport {
+        StepReport {
+            command: step.command.clone(),
+            code: status.code(),
+            signal: signal(status),
+            duration,
+            silent: step.silent,
+            ignore_errors: step.ignore_errors,
+        }
+    }
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+    /// Whether the step failed in a way that stops its instruction, which a
+    /// `-` step never does.
+    pub fn failed(&self) -> bool {
+        !self.success() && !self.ignore_errors
+    }
+}
+
+#[cfg(unix)]
+fn signal(status: &ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn signal(_status: &ExitStatus) -> Option<i32> {
+    None
+}
+
+/// How an instruction went: the steps that ran, in order, and the error
+/// that stopped it, if any.
+#[derive(Debug)]
+pub struct InstructionReport {
+    pub label: String,
+    pub steps: Vec<StepReport>,
+    pub duration: Duration,
+    pub error: Option<Error>,
+}
+
+impl InstructionReport {
+    pub fn new(label: &str) -> InstructionReport {
+        InstructionReport {
+            label: label.to_string(),
+            steps: Vec::new(),
+            duration: Duration::ZERO,
+            error: None,
+        }
+    }
+    pub fn success(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// What became of each instruction of a bake. Instructions are listed in
+/// the order they finished, `skipped` holds the ones that never started.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub instructions: Vec<InstructionReport>,
+    pub skipped: Vec<String>,
+}
+
+impl Report {
+    pub fn success(&self) -> bool {
+        self.instructions.iter().all(|instruction| instruction.success())
+    }
+    pub fn succeeded(&self) -> Vec<String> {
+        self.instructions.iter().filter(|i| i.success()).map(|i| i.label.clone()).collect()
+    }
+    pub fn failed(&self) -> Vec<&InstructionReport> {
+        self.instructions.iter().filter(|i| !i.success()).collect()
+    }
+    /// The error of the instruction that failed first.
+    pub fn error(&self) -> Option<&Error> {
+        self.instructions.iter().find_map(|instruction| instruction.error.as_ref())
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let failed = self.failed().iter().map(|i| i.label.clone()).collect::<Vec<String>>();
+        write!(f, "{} succeeded, {} failed, {} skipped", self.succeeded().len(), failed.len(), self.skipped.len())?;
+        if !failed.is_empty() {
+            write!(f, "\n  failed: {}", failed.join(" "))?;
+        }
+        if !self.skipped.is_empty() {
+            write!(f, "\n  skipped: {}", self.skipped.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// What baking an instruction would do: the shell that would run it and its
+/// steps after substitution.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstructionPlan {
+    pub label: String,
+    pub shell: String,
+    pub steps: Vec<Step>,
+}
+
+/// What a bake would do, without doing it: the instructions in the order
+/// they would be performed and the directory their steps would run in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    pub cwd: String,
+    pub order: Vec<String>,
+    pub instructions: Vec<InstructionPlan>,
+}
+
+impl std::fmt::Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "cwd: {}", self.cwd)?;
+        writeln!(f, "order: {}", self.order.join(" "))?;
+        for instruction in &self.instructions {
+            writeln!(f, "[{}] {}", instruction.label, instruction.shell)?;
+            for step in &instruction.steps {
+                writeln!(f, "{:indent$}{}", "", step, indent = crate::lex::INDENTATION)?;
+            }
+        }
+        Ok(())
+    }
+}